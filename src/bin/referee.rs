@@ -0,0 +1,60 @@
+extern crate tombergm_chess_gui;
+
+use tombergm_chess_gui::cli;
+use tombergm_chess_gui::referee;
+
+const USAGE: & str = "\
+Usage: referee [--bind ADDR:PORT] [--timeout SECS] [--time MINS[+SECS]]
+               [--delay SECS] [--fen FEN]
+
+Hosts any number of games without a window, pairing each client that wants
+to play white with one that wants to play black.
+
+Options:
+  --bind ADDR:PORT              Address to listen on (default 0.0.0.0:8384)
+  --timeout SECS                Drop players that stop responding for six
+                                times SECS seconds, 0 to never do so
+                                (default 10)
+  --time MINS[+SECS]            Give each player MINS minutes, adding SECS
+                                seconds after every move
+  --delay SECS                  Wait SECS seconds before the clock starts
+                                counting down on every move
+  --fen FEN                     Start every game from the position FEN, in
+                                quotes
+  -h, --help                    Show this message
+";
+
+fn main()
+{
+	let args: Vec<String> = std::env::args().skip(1).collect();
+
+	if args.iter().any(|a| a == "-h" || a == "--help")
+	{
+		print!("{}", USAGE);
+		return;
+	}
+
+	let (bind, timeout, clock, fen) = match cli::parse_host(& args)
+	{
+		Ok(cli::Command::Host { uci: Some(_), .. }) =>
+		{
+			eprintln!("error: the referee does not play itself");
+			std::process::exit(2);
+		},
+		Ok(cli::Command::Host { bind, timeout, clock, fen, .. })
+			=> (bind, timeout, clock, fen),
+		Ok(_) => unreachable!(),
+		Err(e) =>
+		{
+			eprintln!("error: {}", e);
+			eprintln!("Try '--help' for more information.");
+			std::process::exit(2);
+		},
+	};
+
+	if let Err(e) = referee::referee_main(bind, timeout, clock, fen)
+	{
+		eprintln!("error: {}", e);
+		std::process::exit(1);
+	}
+}
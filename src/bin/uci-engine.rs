@@ -0,0 +1,42 @@
+extern crate tombergm_chess_gui;
+
+use tombergm_chess_gui::uci;
+
+const USAGE: & str = "\
+Usage: uci-engine
+
+Plays chess with the built in search, speaking UCI on the standard input and
+output, so that it can be loaded into any chess GUI or tournament manager
+that supports UCI engines.
+
+Only games from the standard starting position can be played. A position
+command with any other FEN is refused, and the go that follows it is answered
+with the null move 0000.
+
+Options:
+  -h, --help                    Show this message
+";
+
+fn main()
+{
+	let args: Vec<String> = std::env::args().skip(1).collect();
+
+	if args.iter().any(|a| a == "-h" || a == "--help")
+	{
+		print!("{}", USAGE);
+		return;
+	}
+
+	if let Some(arg) = args.first()
+	{
+		eprintln!("error: unexpected argument '{}'", arg);
+		eprintln!("Try '--help' for more information.");
+		std::process::exit(2);
+	}
+
+	if let Err(e) = uci::uci_main()
+	{
+		eprintln!("error: {}", e);
+		std::process::exit(1);
+	}
+}
@@ -0,0 +1,150 @@
+use crate::driver::{GameDriver, Status};
+use crate::engine::DRAW_MARGIN;
+use crate::fen::Fen;
+use crate::uci::
+{
+	UciEngine,
+	fen_position_command,
+	move_to_uci,
+	position_command,
+};
+use crate::util::*;
+use std::time::Duration;
+
+/* How often the game is checked on */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/* Have the engine pick a move and hand it to the driver */
+fn play_move(driver: & dyn GameDriver, engine: & mut UciEngine)
+	-> Result<(), String>
+{
+	let board = driver.board();
+	let turn = driver.turn();
+	let moves = driver.moves();
+	let played = driver.played();
+
+	/* The engine is given the moves of the game, so that it knows about
+	 * castling, repetitions and the move clock. The client may have
+	 * missed some after a reconnect, and then it gets the position. */
+	let position = if Fen::from_board(& board, & turn, & moves).follow(& played)
+	{
+		position_command(& played)
+	}
+	else
+	{
+		fen_position_command(& driver.fen())
+	};
+
+	/* Stop thinking if the game ends or the connection is lost in the
+	 * meantime */
+	let thought = engine.go
+	(
+		& position,
+		& board,
+		& ||
+		driver.joever() != cnp::Joever::Ongoing
+			|| driver.status() != Status::Connected,
+	);
+
+	let thought = match thought.map_err(|e| e.to_string())?
+	{
+		Some(thought) => thought,
+		None => return Ok(()),
+	};
+
+	/* Take the opponent's offer if the engine is worse off, moving
+	 * declines it */
+	if driver.draw_offer() == Some(inv_color(& turn))
+		&& thought.score.map_or(false, |s| s < -DRAW_MARGIN)
+	{
+		println!("Engine accepts the draw");
+		driver.offer_draw();
+
+		return Ok(());
+	}
+
+	if driver.features().contains(& cnp::Features::PossibleMoveGeneration)
+		&& !moves.contains(& thought.best)
+	{
+		return Err(format!
+		(
+			"the engine made an illegal move, {}",
+			move_to_uci(& thought.best),
+		));
+	}
+
+	println!("Engine plays {}", move_to_uci(& thought.best));
+	driver.set_next_move(Some(thought.best));
+
+	Ok(())
+}
+
+/* Play a network game without a window, with the engine making the moves
+ * at this end instead of the mouse. Returns how the game ended, or why it
+ * could not be played to the end. */
+pub fn bridge_main(mut driver: Box<dyn GameDriver>, mut engine: UciEngine)
+	-> Result<cnp::Joever, String>
+{
+	let mut status = Status::Connecting;
+	let mut message = String::new();
+
+	let result = loop
+	{
+		/* Pass on what the driver has to say */
+		let s = driver.status();
+		if s != status
+		{
+			println!("{}", s.describe());
+			status = s;
+		}
+
+		let m = driver.message();
+		if m != message
+		{
+			if !m.is_empty()
+			{
+				println!("{}", m);
+			}
+			message = m;
+		}
+
+		let joever = driver.joever();
+		if joever != cnp::Joever::Ongoing
+		{
+			break Ok(joever);
+		}
+
+		/* Reconnecting is worth waiting for, the other errors end the
+		 * game */
+		match status
+		{
+			Status::Reconnecting(_) => (),
+			ref s if s.is_error() => break Err(s.describe()),
+			Status::Closed => break Err(status.describe()),
+			_ => (),
+		}
+
+		/* The engine stands by its moves */
+		if driver.takeback_request() == Some(inv_color(& driver.color()))
+		{
+			println!("Engine refuses the takeback");
+			driver.answer_takeback(false);
+		}
+
+		if status == Status::Connected
+			&& driver.turn() == driver.color()
+			&& driver.get_next_move().is_none()
+		{
+			if let Err(e) = play_move(driver.as_ref(), & mut engine)
+			{
+				break Err(e);
+			}
+		}
+
+		std::thread::sleep(POLL_INTERVAL);
+	};
+
+	driver.quit();
+
+	result
+}
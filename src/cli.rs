@@ -0,0 +1,613 @@
+use crate::clock::TimeControl;
+use crate::fen::Fen;
+use crate::fen;
+use crate::search::Limits;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 8384;
+pub const DEFAULT_TIMEOUT: u64 = 10;
+pub const DEFAULT_MOVETIME: u64 = 5;
+
+pub const USAGE: & str = "\
+Usage: chess-gui <command> [options]
+
+Commands:
+  host [--bind ADDR:PORT]       Host a network game (default 0.0.0.0:8384)
+  join HOST[:PORT] [--color C]  Join a network game, C is white, black or
+                                random (default white)
+  join HOST[:PORT] --spectate   Watch a network game
+  local [--flip]                Play both sides on this machine, turning
+                                the board towards the player to move if
+                                --flip is given
+  ai [--color C]                Play against the computer, C is white,
+                                black or random (default white)
+  ai --uci PATH                 Play against a UCI engine
+  view FILE [--game N]          Step through the games of a PGN file,
+                                starting at game N (default 1)
+  view FILE --list              List the games of a PGN file
+
+Network options:
+  --uci PATH                    Have a UCI engine play this end of a hosted
+                                or joined game, without a window
+  --timeout SECS                Report the opponent as not responding after
+                                SECS seconds of silence, 0 to never do so
+                                (default 10)
+
+Game options, for hosting and local games:
+  --fen FEN                     Start from the position FEN, in quotes
+  --time MINS[+SECS]            Give each player MINS minutes, adding SECS
+                                seconds after every move
+  --delay SECS                  Wait SECS seconds before the clock starts
+                                counting down on every move
+
+Computer options, for ai and --uci:
+  --depth N                     Search N moves ahead
+  --nodes N                     Search at most N positions per move
+  --movetime SECS               Think for at most SECS seconds per move,
+                                0 for no limit (default 5)
+
+Options:
+  -h, --help                    Show this message
+
+Keys:
+  S                             Save the game as PGN, which also happens
+                                when the game ends
+  F                             Print the position as FEN and add it to a
+                                file of positions from the game
+  U                             Take back a move, in network games ask the
+                                opponent to take back the last move of
+                                each player
+  R                             Play a move that was taken back again
+  Left, Right                   Go to the previous or next move of a game
+                                that is being viewed
+  Up, Down                      Go to the first or last move
+  Page Up, Page Down            Go to the previous or next game in the file
+  Escape                        Quit
+";
+
+pub enum Command
+{
+	Host
+	{
+		bind	: String,
+		timeout	: Option<Duration>,
+		clock	: Option<TimeControl>,
+		uci	: Option<String>,
+		limits	: Limits,
+		fen	: Option<Fen>,
+	},
+	Join
+	{
+		addr	: String,
+		color	: Option<cnp::Color>,
+		timeout	: Option<Duration>,
+		spectate: bool,
+		uci	: Option<String>,
+		limits	: Limits,
+	},
+	Local
+	{
+		flip	: bool,
+		clock	: Option<TimeControl>,
+		fen	: Option<Fen>,
+	},
+	Ai
+	{
+		color	: Option<cnp::Color>,
+		limits	: Limits,
+		uci	: Option<String>,
+	},
+	View
+	{
+		path	: String,
+		game	: usize,
+		list	: bool,
+	},
+	Help,
+}
+
+/* Append the default port to an address that has none, taking care not to
+ * mistake the colons of an IPv6 address for a port separator */
+pub fn with_default_port(addr: & str) -> String
+{
+	if addr.starts_with('[')
+	{
+		if addr.contains("]:")
+		{
+			String::from(addr)
+		}
+		else
+		{
+			format!("{}:{}", addr, DEFAULT_PORT)
+		}
+	}
+	else if addr.parse::<Ipv6Addr>().is_ok()
+	{
+		format!("[{}]:{}", addr, DEFAULT_PORT)
+	}
+	else if addr.contains(':')
+	{
+		String::from(addr)
+	}
+	else
+	{
+		format!("{}:{}", addr, DEFAULT_PORT)
+	}
+}
+
+fn parse_color(s: & str) -> Result<Option<cnp::Color>, String>
+{
+	match s
+	{
+		"white"		=> Ok(Some(cnp::Color::White)),
+		"black"		=> Ok(Some(cnp::Color::Black)),
+		"random"	=> Ok(None),
+		_		=> Err(format!("invalid color '{}'", s)),
+	}
+}
+
+fn parse_timeout(s: & str) -> Result<Option<Duration>, String>
+{
+	match s.parse::<u64>()
+	{
+		Ok(0) => Ok(None),
+		Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+		Err(_) => Err(format!("invalid timeout '{}'", s)),
+	}
+}
+
+fn parse_secs(s: & str) -> Result<Duration, String>
+{
+	match s.parse::<f64>()
+	{
+		Ok(secs) if secs >= 0. && secs.is_finite()
+			=> Ok(Duration::from_secs_f64(secs)),
+		_ => Err(format!("invalid number of seconds '{}'", s)),
+	}
+}
+
+/* Parse a time control such as 5 or 5+3, in minutes plus seconds of
+ * increment */
+fn parse_time(s: & str) -> Result<TimeControl, String>
+{
+	let (base, increment) = match s.split_once('+')
+	{
+		Some((base, increment)) => (base, parse_secs(increment)?),
+		None => (s, Duration::ZERO),
+	};
+
+	let base = match base.parse::<f64>()
+	{
+		Ok(mins) if mins > 0. && mins.is_finite()
+			=> Duration::from_secs_f64(mins * 60.),
+		_ => return Err(format!("invalid time control '{}'", s)),
+	};
+
+	Ok(TimeControl { base, increment, delay: Duration::ZERO })
+}
+
+/* Apply a delay given with --delay to the time control given with
+ * --time */
+fn with_delay(clock: Option<TimeControl>, delay: Option<Duration>)
+	-> Result<Option<TimeControl>, String>
+{
+	match (clock, delay)
+	{
+		(Some(mut clock), Some(delay)) =>
+		{
+			clock.delay = delay;
+
+			Ok(Some(clock))
+		},
+		(None, Some(_)) => Err(String::from("--delay needs --time")),
+		(clock, None) => Ok(clock),
+	}
+}
+
+pub fn parse_host(args: & [String]) -> Result<Command, String>
+{
+	let mut bind = format!("0.0.0.0:{}", DEFAULT_PORT);
+	let mut timeout = Some(Duration::from_secs(DEFAULT_TIMEOUT));
+	let mut clock = None;
+	let mut delay = None;
+	let mut uci = None;
+	let mut limits = default_limits();
+	let mut fen = None;
+	let mut i = 0;
+
+	while i < args.len()
+	{
+		if parse_limits(args, & mut i, & mut limits)?
+		{
+			i += 1;
+			continue;
+		}
+
+		match args[i].as_str()
+		{
+			"--bind" =>
+			{
+				i += 1;
+				bind = args.get(i)
+					.ok_or("--bind needs an address")?
+					.clone();
+			},
+			"--timeout" =>
+			{
+				i += 1;
+				timeout = parse_timeout
+				(
+					args.get(i)
+						.ok_or("--timeout needs a value")?
+				)?;
+			},
+			"--time" =>
+			{
+				i += 1;
+				clock = Some(parse_time
+				(
+					args.get(i)
+						.ok_or("--time needs a time control")?
+				)?);
+			},
+			"--delay" =>
+			{
+				i += 1;
+				delay = Some(parse_secs
+				(
+					args.get(i)
+						.ok_or("--delay needs a value")?
+				)?);
+			},
+			"--uci" =>
+			{
+				i += 1;
+				uci = Some(parse_uci(args.get(i))?);
+			},
+			"--fen" =>
+			{
+				i += 1;
+				fen = Some(parse_fen(args.get(i))?);
+			},
+			a => return Err(format!("unexpected argument '{}'", a)),
+		}
+
+		i += 1;
+	}
+
+	if bind.parse::<SocketAddr>().is_err()
+	{
+		return Err(format!("invalid bind address '{}'", bind));
+	}
+
+	let clock = with_delay(clock, delay)?;
+	check_limits(& limits)?;
+
+	Ok(Command::Host { bind, timeout, clock, uci, limits, fen })
+}
+
+fn parse_join(args: & [String]) -> Result<Command, String>
+{
+	let mut addr = None;
+	let mut color = Some(cnp::Color::White);
+	let mut spectate = false;
+	let mut timeout = Some(Duration::from_secs(DEFAULT_TIMEOUT));
+	let mut uci = None;
+	let mut limits = default_limits();
+	let mut i = 0;
+
+	while i < args.len()
+	{
+		if parse_limits(args, & mut i, & mut limits)?
+		{
+			i += 1;
+			continue;
+		}
+
+		match args[i].as_str()
+		{
+			"--color" =>
+			{
+				i += 1;
+				color = parse_color
+				(
+					args.get(i)
+						.ok_or("--color needs a color")?
+				)?;
+			},
+			"--timeout" =>
+			{
+				i += 1;
+				timeout = parse_timeout
+				(
+					args.get(i)
+						.ok_or("--timeout needs a value")?
+				)?;
+			},
+			"--spectate" =>
+			{
+				spectate = true;
+			},
+			"--uci" =>
+			{
+				i += 1;
+				uci = Some(parse_uci(args.get(i))?);
+			},
+			a if a.starts_with('-') =>
+			{
+				return Err(format!("unknown option '{}'", a));
+			},
+			a if addr.is_none() =>
+			{
+				addr = Some(with_default_port(a));
+			},
+			a => return Err(format!("unexpected argument '{}'", a)),
+		}
+
+		i += 1;
+	}
+
+	let addr = addr.ok_or("join needs a host to connect to")?;
+
+	/* The host part is resolved when connecting, only the port can be
+	 * checked here */
+	let port = addr.rsplit(':').next().unwrap_or("");
+	if port.parse::<u16>().is_err()
+	{
+		return Err(format!("invalid port in '{}'", addr));
+	}
+
+	if spectate && uci.is_some()
+	{
+		return Err(String::from("an engine can not spectate"));
+	}
+
+	check_limits(& limits)?;
+
+	Ok(Command::Join { addr, color, timeout, spectate, uci, limits })
+}
+
+fn parse_local(args: & [String]) -> Result<Command, String>
+{
+	let mut flip = false;
+	let mut clock = None;
+	let mut delay = None;
+	let mut fen = None;
+	let mut i = 0;
+
+	while i < args.len()
+	{
+		match args[i].as_str()
+		{
+			"--flip" =>
+			{
+				flip = true;
+			},
+			"--time" =>
+			{
+				i += 1;
+				clock = Some(parse_time
+				(
+					args.get(i)
+						.ok_or("--time needs a time control")?
+				)?);
+			},
+			"--delay" =>
+			{
+				i += 1;
+				delay = Some(parse_secs
+				(
+					args.get(i)
+						.ok_or("--delay needs a value")?
+				)?);
+			},
+			"--fen" =>
+			{
+				i += 1;
+				fen = Some(parse_fen(args.get(i))?);
+			},
+			a => return Err(format!("unexpected argument '{}'", a)),
+		}
+
+		i += 1;
+	}
+
+	let clock = with_delay(clock, delay)?;
+
+	Ok(Command::Local { flip, clock, fen })
+}
+
+fn parse_count(s: & str) -> Result<u64, String>
+{
+	match s.parse::<u64>()
+	{
+		Ok(n) if n > 0 => Ok(n),
+		_ => Err(format!("invalid number '{}'", s)),
+	}
+}
+
+/* Parse the options that set how hard the computer thinks, returns false
+ * if the option at i is not one of them */
+pub fn parse_limits(args: & [String], i: & mut usize, limits: & mut Limits)
+	-> Result<bool, String>
+{
+	match args[*i].as_str()
+	{
+		"--depth" =>
+		{
+			*i += 1;
+			limits.depth = Some(parse_count
+			(
+				args.get(*i)
+					.ok_or("--depth needs a value")?
+			)? as u32);
+		},
+		"--nodes" =>
+		{
+			*i += 1;
+			limits.nodes = Some(parse_count
+			(
+				args.get(*i)
+					.ok_or("--nodes needs a value")?
+			)?);
+		},
+		"--movetime" =>
+		{
+			*i += 1;
+			limits.time = Some(parse_secs
+			(
+				args.get(*i)
+					.ok_or("--movetime needs a value")?
+			)?).filter(|t| !t.is_zero());
+		},
+		_ => return Ok(false),
+	}
+
+	Ok(true)
+}
+
+fn parse_fen(arg: Option<& String>) -> Result<Fen, String>
+{
+	let arg = arg.ok_or("--fen needs a position")?;
+
+	fen::parse(arg).map_err(|e| format!("invalid FEN '{}': {}", arg, e))
+}
+
+fn parse_uci(arg: Option<& String>) -> Result<String, String>
+{
+	Ok(arg.ok_or("--uci needs the path of an engine")?.clone())
+}
+
+/* A search with no limits would never end */
+pub fn check_limits(limits: & Limits) -> Result<(), String>
+{
+	if limits.depth.is_none()
+		&& limits.nodes.is_none()
+		&& limits.time.is_none()
+	{
+		return Err(String::from
+			("the computer needs a depth, node or time limit"));
+	}
+
+	Ok(())
+}
+
+pub fn default_limits() -> Limits
+{
+	Limits
+	{
+		depth	: None,
+		nodes	: None,
+		time	: Some(Duration::from_secs(DEFAULT_MOVETIME)),
+	}
+}
+
+fn parse_ai(args: & [String]) -> Result<Command, String>
+{
+	let mut color = Some(cnp::Color::White);
+	let mut limits = default_limits();
+	let mut uci = None;
+	let mut i = 0;
+
+	while i < args.len()
+	{
+		if parse_limits(args, & mut i, & mut limits)?
+		{
+			i += 1;
+			continue;
+		}
+
+		match args[i].as_str()
+		{
+			"--color" =>
+			{
+				i += 1;
+				color = parse_color
+				(
+					args.get(i)
+						.ok_or("--color needs a color")?
+				)?;
+			},
+			"--uci" =>
+			{
+				i += 1;
+				uci = Some(parse_uci(args.get(i))?);
+			},
+			a => return Err(format!("unexpected argument '{}'", a)),
+		}
+
+		i += 1;
+	}
+
+	check_limits(& limits)?;
+
+	Ok(Command::Ai { color, limits, uci })
+}
+
+/* Parse the arguments of the view command, which come after its name */
+fn parse_view(args: & [String]) -> Result<Command, String>
+{
+	let mut path = None;
+	let mut game = 1;
+	let mut list = false;
+	let mut i = 0;
+
+	while i < args.len()
+	{
+		match args[i].as_str()
+		{
+			"--game" =>
+			{
+				i += 1;
+				game = parse_count
+				(
+					args.get(i)
+						.ok_or("--game needs a value")?
+				)? as usize;
+			},
+			"--list" =>
+			{
+				list = true;
+			},
+			a if a.starts_with("--") =>
+			{
+				return Err(format!("unknown option '{}'", a));
+			},
+			a if path.is_none() =>
+			{
+				path = Some(String::from(a));
+			},
+			a => return Err(format!("unexpected argument '{}'", a)),
+		}
+
+		i += 1;
+	}
+
+	Ok(Command::View
+	{
+		path	: path.ok_or("view needs a file")?,
+		game,
+		list,
+	})
+}
+
+/* Parse the command line arguments, not including the program name */
+pub fn parse(args: & [String]) -> Result<Command, String>
+{
+	if args.iter().any(|a| a == "-h" || a == "--help")
+	{
+		return Ok(Command::Help);
+	}
+
+	match args.first().map(|a| a.as_str())
+	{
+		Some("host")	=> parse_host(& args[1..]),
+		Some("join")	=> parse_join(& args[1..]),
+		Some("local")	=> parse_local(& args[1..]),
+		Some("ai")	=> parse_ai(& args[1..]),
+		Some("view")	=> parse_view(& args[1..]),
+		Some(a)		=> Err(format!("unknown command '{}'", a)),
+		None		=> Err(String::from("no command given")),
+	}
+}
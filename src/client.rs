@@ -1,13 +1,26 @@
 extern crate scrappy_chess;
 extern crate serde;
 
-use crate::driver::GameDriver;
+use crate::clock::{Clock, ClockSync, Timed};
+use crate::driver::{GameDriver, Status};
+use crate::handshake::{ClientHandshake, new_token};
 use std::net::TcpStream;
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::net;
+use crate::san::move_to_san;
+use crate::takeback::{self, Message, Takeback};
 use crate::util::*;
 
+const RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_DELAY_MAX: Duration
+	= Duration::from_secs(8);
+/* How often the player is checked on while the server waits for an
+ * answer */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 struct State
 {
 	features	: Vec<cnp::Features>,
@@ -17,8 +30,30 @@ struct State
 	turn		: cnp::Color,
 	moves		: Vec<cnp::Move>,
 	next_move	: Option<cnp::Move>,
+	spectator	: bool,
+	resign		: bool,
+	offer_draw	: bool,
+	draw_offer	: Option<cnp::Color>,
+	takebacks	: bool,
+	ask_takeback	: bool,
+	takeback	: Option<cnp::Color>,
+	answer		: Option<bool>,
+	clock		: Option<Clock>,
+	/* The clock times that came ahead of a message, which are taken once
+	 * the message has been dealt with */
+	times		: Option<ClockSync>,
+	history		: Vec<String>,
+	/* Each move in the history, and the board before it */
+	played		: Vec<cnp::Move>,
+	boards		: Vec<[[cnp::Piece; 8]; 8]>,
 
+	/* The server has let us in, so a lost connection can be resumed by
+	 * showing the same token */
+	joined		: bool,
+	token		: String,
 	stream		: Option<TcpStream>,
+	status		: Status,
+	responding	: bool,
 	message		: String,
 	quit		: bool,
 }
@@ -36,36 +71,136 @@ impl State
 			turn		: cnp::Color::White,
 			moves		: Vec::new(),
 			next_move	: None,
+			spectator	: false,
+			resign		: false,
+			offer_draw	: false,
+			draw_offer	: None,
+			takebacks	: false,
+			ask_takeback	: false,
+			takeback	: None,
+			answer		: None,
+			clock		: None,
+			times		: None,
+			history		: Vec::new(),
+			played		: Vec::new(),
+			boards		: Vec::new(),
 
+			joined		: false,
+			token		: new_token(),
 			stream		: None,
+			status		: Status::Connecting,
+			responding	: true,
 			message		: String::new(),
 			quit		: false,
 		}
 	}
 
+	/* Take on the board and moves that the server sends. The server does
+	 * not say which move was played, so it is looked for among the moves
+	 * that were possible before, or worked out from the squares that
+	 * changed if the server does not list them. A board from before the
+	 * last move of each player means that they were taken back. */
+	fn set_board(self: & mut Self,
+			board: & [[cnp::Piece; 8]; 8],
+			moves: & [cnp::Move])
+	{
+		if self.board != * board
+		{
+			let played = if self.moves.is_empty()
+			{
+				move_between(& self.board, board)
+			}
+			else
+			{
+				self.moves
+					.iter()
+					.find(|m| apply_move(& self.board, m) == * board)
+					.copied()
+			};
+			let n = self.boards.len();
+
+			if let Some(m) = played
+			{
+				let mut san = move_to_san
+					(& self.board, & m, & self.moves, moves);
+
+				/* A mate can not be told from a check without
+				 * the moves */
+				if moves.is_empty() && self.moves.is_empty()
+				{
+					san = san.replace('#', "+");
+				}
+
+				self.history.push(san);
+				self.played.push(m);
+				self.boards.push(self.board);
+			}
+			else if n >= 2 && self.boards[n - 2] == * board
+			{
+				self.history.truncate(n - 2);
+				self.played.truncate(n - 2);
+				self.boards.truncate(n - 2);
+			}
+		}
+
+		self.board = * board;
+		self.moves = moves.to_vec();
+	}
+
+	/* The clock follows the turn, the server keeps the real time and says
+	 * when a player runs out of it */
 	fn pass_turn(self: & mut Self)
 	{
 		self.turn = inv_color(& self.turn);
+
+		if let Some(ref mut clock) = self.clock
+		{
+			clock.press();
+		}
+	}
+
+	/* Set the clock to the times that the server sent last */
+	fn sync_clock(self: & mut Self)
+	{
+		if let (Some(clock), Some(times)) = (& mut self.clock, self.times)
+		{
+			clock.sync(& times);
+		}
+
+		self.times = None;
 	}
 }
 
 struct Client
 {
-	stream		: TcpStream,
+	peer		: net::Peer,
 	state_rc	: Arc<Mutex<State>>,
 }
 
 impl Client
 {
-	/* Receive and deserialize a data type from the server */
-	fn read<T>(self: & Self)
+	/* Receive and deserialize a data type from the server, reply tells if
+	 * the server should be answering promptly. The clock times that the
+	 * server sends ahead of it are kept for later. */
+	fn read<T>(self: & Self, reply: bool)
 		-> Result<T, serde_json::Error>
 		where T: for<'de> serde::de::Deserialize<'de>
 	{
-		let mut de = serde_json::Deserializer
-			::from_reader(& self.stream);
+		loop
+		{
+			let msg = self.peer.read::<Timed<T>, _>
+			(
+				reply,
+				|quiet| self.mutate(|state| state.responding = !quiet),
+			)?;
 
-		T::deserialize(& mut de)
+			match msg
+			{
+				Timed::Clock(times) =>
+					self.mutate(|state| state.times = Some(times)),
+				Timed::Message(msg) => return Ok(msg),
+			}
+		}
 	}
 
 	/* Serialize and send a data type to the server */
@@ -73,7 +208,7 @@ impl Client
 		-> Result<(), serde_json::Error>
 		where T: serde::ser::Serialize
 	{
-		serde_json::to_writer(& self.stream, t)
+		self.peer.write(t)
 	}
 
 	/* Acquire and mutate the local state in a closure */
@@ -155,7 +290,147 @@ impl GameDriver for ClientDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 
-		state.next_move = m;
+		if !state.spectator
+		{
+			state.next_move = m;
+		}
+	}
+
+	fn resign(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.joever == cnp::Joever::Ongoing && !state.spectator
+		{
+			state.resign = true;
+
+			if state.turn != state.color
+			{
+				state.message = String
+					::from("Resigning on your turn");
+			}
+		}
+	}
+
+	fn offer_draw(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.joever == cnp::Joever::Ongoing
+			&& !state.spectator
+			&& state.draw_offer != Some(copy_color(& state.color))
+		{
+			state.offer_draw = true;
+
+			if state.draw_offer.is_none()
+			{
+				state.message = String::from
+				("A draw will be offered with your next move");
+			}
+		}
+	}
+
+	fn decline_draw(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		/* The opponent is told by the next move that is sent */
+		if state.draw_offer == Some(inv_color(& state.color))
+		{
+			state.draw_offer = None;
+			state.message = String::from("Draw declined");
+		}
+	}
+
+	fn draw_offer(self: & Self) -> Option<cnp::Color>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.draw_offer.as_ref().map(copy_color)
+	}
+
+	fn clock(self: & Self) -> Option<Clock>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.clock
+	}
+
+	fn history(self: & Self) -> Vec<String>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.history.clone()
+	}
+
+	fn played(self: & Self) -> Vec<cnp::Move>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.played.clone()
+	}
+
+	/* Ask the server to take back the last move of each player, which is
+	 * done on the player's turn */
+	fn undo(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.joever == cnp::Joever::Ongoing
+			&& !state.spectator
+			&& state.takeback.is_none()
+		{
+			state.ask_takeback = true;
+
+			if state.turn != state.color
+			{
+				state.message = String::from
+				("A takeback will be asked for on your turn");
+			}
+		}
+	}
+
+	fn takeback_request(self: & Self) -> Option<cnp::Color>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.takeback.as_ref().map(copy_color)
+	}
+
+	fn answer_takeback(self: & Self, accept: bool)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.takeback == Some(inv_color(& state.color))
+		{
+			state.answer = Some(accept);
+		}
+	}
+
+	fn spectating(self: & Self) -> bool
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.spectator
+	}
+
+	fn status(self: & Self) -> Status
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.status.clone()
 	}
 
 	fn message(self: & Self) -> String
@@ -163,7 +438,14 @@ impl GameDriver for ClientDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 
-		state.message.clone()
+		if !state.responding
+		{
+			String::from("Opponent not responding")
+		}
+		else
+		{
+			state.message.clone()
+		}
 	}
 
 	fn quit(self: & mut Self)
@@ -203,8 +485,7 @@ fn parse_msg(m: & cnp::ServerToClient, s: & mut State)
 			..
 		} =>
 		{
-			s.board = *board;
-			s.moves = moves.clone();
+			s.set_board(board, moves);
 			s.joever = *joever;
 		},
 		cnp::ServerToClient::Error
@@ -212,12 +493,12 @@ fn parse_msg(m: & cnp::ServerToClient, s: & mut State)
 			board,
 			moves,
 			joever,
-			..
+			message,
 		} =>
 		{
-			s.board = *board;
-			s.moves = moves.clone();
+			s.set_board(board, moves);
 			s.joever = *joever;
+			s.message = message.clone();
 		},
 		cnp::ServerToClient::Resigned
 		{
@@ -236,17 +517,16 @@ fn parse_msg(m: & cnp::ServerToClient, s: & mut State)
 			..
 		} =>
 		{
-			s.board = *board;
-			s.moves = moves.clone();
+			s.set_board(board, moves);
 		},
 	}
 }
 
-fn recv_msg(client: & Client)
+fn recv_msg(client: & Client, reply: bool)
 	-> Result<cnp::ServerToClient, serde_json::Error>
 {
 	/* Receive a message from the server */
-	let msg = client.read::<cnp::ServerToClient>()?;
+	let msg = client.read::<cnp::ServerToClient>(reply)?;
 	println!("Message received");
 
 	/* Update the state with the received message */
@@ -255,25 +535,343 @@ fn recv_msg(client: & Client)
 	Ok(msg)
 }
 
+/* Receive the server's answer to a message sent on our turn. A draw offer
+ * that the server sent after its last move may come first, the player
+ * has moved on from it so it is passed over. */
+fn recv_reply(client: & Client, reply: bool)
+	-> Result<cnp::ServerToClient, serde_json::Error>
+{
+	loop
+	{
+		match recv_msg(client, reply)?
+		{
+			cnp::ServerToClient::Draw {..} =>
+			{
+				println!("Passed over draw offer");
+			},
+			msg => return Ok(msg),
+		}
+	}
+}
+
+/* Handle a message that the server sends on our turn without being asked,
+ * which offers a draw after its move or ends the game */
+fn recv_unasked(client: & Client)
+	-> Result<(), serde_json::Error>
+{
+	if let cnp::ServerToClient::Draw {..} = recv_msg(client, true)?
+	{
+		client.mutate
+		(
+			|state|
+			{
+				state.draw_offer = Some(inv_color(& state.color));
+				state.message = String::from("Opponent offers a draw");
+			}
+		);
+	}
+
+	Ok(())
+}
+
+fn send_resign(client: & Client)
+	-> Result<(), serde_json::Error>
+{
+	/* Send the resignation */
+	client.write(& cnp::ClientToServer::Resign)?;
+	println!("Resignation sent");
+
+	/* Receive the server's acknowledgement */
+	println!("Waiting for response");
+	if let cnp::ServerToClient::Resigned {..} = recv_reply(client, true)?
+	{
+		client.mutate
+		(
+			|state| state.message = String::from("You resigned")
+		);
+	}
+
+	Ok(())
+}
+
+/* Ask the server to take back a move and wait for its answer */
+fn send_takeback(client: & Client)
+	-> Result<(), serde_json::Error>
+{
+	let ask = client.mutate
+	(
+		|state|
+		{
+			state.ask_takeback = false;
+
+			/* The server knows if there are moves to take back,
+			 * the history may be missing some after a reconnect */
+			if !state.takebacks
+			{
+				state.message = String::from
+					("The server can not take back moves");
+
+				return false;
+			}
+
+			state.takeback = Some(copy_color(& state.color));
+			state.message = String::from("Takeback requested");
+
+			true
+		}
+	);
+
+	if !ask
+	{
+		return Ok(());
+	}
+
+	client.write(& Takeback::Request)?;
+	println!("Takeback request sent");
+
+	/* The server's player takes their time to answer, the message of a
+	 * refusal has already been copied */
+	println!("Waiting for response");
+	let msg = recv_reply(client, false)?;
+
+	client.mutate
+	(
+		|state|
+		{
+			state.takeback = None;
+
+			if let cnp::ServerToClient::State {..} = msg
+			{
+				state.draw_offer = None;
+				state.message = String::from("Takeback accepted");
+			}
+		}
+	);
+
+	Ok(())
+}
+
+/* Handle a takeback message that the server sends on its turn */
+fn recv_takeback(client: & Client, t: Takeback)
+	-> Result<(), serde_json::Error>
+{
+	if t != Takeback::Request || !client.mutate(|state| state.takebacks)
+	{
+		return Err(takeback::unexpected(& t));
+	}
+
+	println!("Takeback request received");
+
+	client.mutate
+	(
+		|state|
+		{
+			state.takeback = Some(inv_color(& state.color));
+			state.answer = None;
+			state.message = String::from
+				("Opponent asks to take back a move");
+		}
+	);
+
+	/* Wait for the player to answer, while the server's clock runs. The
+	 * server says so if it runs out in the meantime. */
+	let accept = loop
+	{
+		let (quit, flagged, answer) = client.mutate
+		(
+			|state|
+			(
+				state.quit,
+				state.clock.map_or(false, |c| c.flagged().is_some()),
+				state.answer.take(),
+			)
+		);
+
+		if quit || flagged
+		{
+			client.mutate(|state| state.takeback = None);
+
+			return Ok(());
+		}
+
+		if let Some(accept) = answer
+		{
+			break accept;
+		}
+
+		client.peer.heartbeat()?;
+		std::thread::sleep(POLL_INTERVAL);
+	};
+
+	if !accept
+	{
+		client.write(& Takeback::Refuse)?;
+		println!("Takeback refused");
+
+		client.mutate
+		(
+			|state|
+			{
+				state.takeback = None;
+				state.message = String::from("Takeback refused");
+			}
+		);
+
+		return Ok(());
+	}
+
+	client.write(& Takeback::Accept)?;
+	println!("Takeback accepted");
+
+	/* The server follows up with the position after the takeback */
+	println!("Waiting for response");
+	let msg = recv_msg(client, true)?;
+
+	client.mutate
+	(
+		|state|
+		{
+			state.takeback = None;
+
+			if let cnp::ServerToClient::State {..} = msg
+			{
+				state.draw_offer = None;
+				state.message = String::from("Takeback accepted");
+			}
+		}
+	);
+
+	Ok(())
+}
+
 fn send_move(client: & Client)
 	-> Result<(), serde_json::Error>
 {
+	/* See what the server has to say first, it may have offered a draw
+	 * or ended the game */
+	if client.peer.pending().map_err(serde_json::Error::io)?
+	{
+		return recv_unasked(client);
+	}
+
+	/* Wait for the server to end the game if the player has run out of
+	 * time */
+	let flagged = client.mutate
+	(
+		|state|
+		match state.clock
+		{
+			Some(clock) => clock.flagged() == Some
+				(copy_color(& state.color)),
+			None => false,
+		}
+	);
+
+	if flagged
+	{
+		println!("Waiting for flag fall");
+		recv_msg(client, true)?;
+
+		return Ok(());
+	}
+
+	/* Resign instead of moving if the player asked to */
+	if client.mutate(|state| state.resign)
+	{
+		return send_resign(client);
+	}
+
+	/* Ask the server to take back a move if the player asked to */
+	if client.mutate(|state| state.ask_takeback)
+	{
+		return send_takeback(client);
+	}
+
+	/* Accept the opponent's draw offer if the player asked to */
+	let accept = client.mutate
+	(
+		|state|
+		state.offer_draw
+			&& state.draw_offer == Some(inv_color(& state.color))
+	);
+
+	if accept
+	{
+		/* The server ends the game without responding */
+		client.write(& cnp::ClientToServer::Draw)?;
+		println!("Draw sent");
+
+		client.mutate
+		(
+			|state|
+			{
+				state.joever = cnp::Joever::Draw;
+				state.offer_draw = false;
+				state.draw_offer = None;
+				state.message = String::from("Draw agreed");
+			}
+		);
+
+		return Ok(());
+	}
+
 	/* Check if there is a move to send */
 	let next_move = client.mutate(|state| state.next_move);
 
+	/* Keep the server aware that we are still here while the player
+	 * thinks */
+	if next_move.is_none()
+	{
+		client.peer.heartbeat()?;
+	}
+
 	if let Some(m) = next_move
 	{
+		/* Offer a draw ahead of the move if the player asked to */
+		if client.mutate(|state| state.offer_draw)
+		{
+			client.write(& cnp::ClientToServer::Draw)?;
+			println!("Draw sent");
+
+			client.mutate
+			(
+				|state|
+				{
+					state.offer_draw = false;
+					state.draw_offer
+						= Some(copy_color(& state.color));
+					state.message
+						= String::from("Draw offered");
+				}
+			);
+		}
+
 		/* Send the next move */
 		client.write(& cnp::ClientToServer::Move(m))?;
 		println!("Move sent");
 
 		/* Receive the server's respone */
 		println!("Waiting for response");
-		match recv_msg(client)?
+		match recv_reply(client, true)?
 		{
 			cnp::ServerToClient::State {..} =>
 			{
-				client.mutate(|state| state.pass_turn());
+				client.mutate
+				(
+					|state|
+					{
+						state.pass_turn();
+
+						/* Moving declines the opponent's
+						 * offer */
+						if state.draw_offer
+							== Some(copy_color
+							(& state.turn))
+						{
+							state.draw_offer = None;
+						}
+					}
+				);
 			},
 			_ => (),
 		}
@@ -288,125 +886,453 @@ fn send_move(client: & Client)
 fn recv_move(client: & Client)
 	-> Result<(), serde_json::Error>
 {
-	/* Receive the server's next move */
+	/* Receive the server's next move, or a takeback request */
 	println!("Waiting for move");
-	recv_msg(client)?;
+	let msg = match client.read::<Message<cnp::ServerToClient>>(false)?
+	{
+		Message::Protocol(msg) => msg,
+		Message::Takeback(t) => return recv_takeback(client, t),
+	};
+	println!("Message received");
+
+	let before = client.mutate(|state| state.board);
+	client.mutate(|state| parse_msg(& msg, state));
+
+	match msg
+	{
+		cnp::ServerToClient::Resigned {..} =>
+		{
+			client.mutate
+			(
+				|state| state.message
+				= String::from("Opponent resigned")
+			);
+		},
+		cnp::ServerToClient::Error {..} =>
+		{
+			/* The only error that comes unasked is the one that
+			 * says that someone ran out of time, its message has
+			 * already been copied */
+			println!("Error received");
+		},
+		cnp::ServerToClient::Draw {..} =>
+		{
+			client.mutate
+			(
+				|state|
+				{
+					let color = copy_color(& state.color);
 
-	client.mutate(|state| state.pass_turn());
+					if state.draw_offer == Some(color)
+					{
+						/* Our offer was accepted */
+						state.joever = cnp::Joever::Draw;
+						state.draw_offer = None;
+						state.message = String
+							::from("Draw agreed");
+					}
+					else
+					{
+						/* The offer comes with the
+						 * server's move, either after
+						 * its state or in place of it */
+						if state.board != before
+						{
+							state.pass_turn();
+						}
+
+						state.draw_offer = Some
+							(inv_color(& state.color));
+						state.message = String::from
+							("Opponent offers a draw");
+					}
+				}
+			);
+		},
+		_ =>
+		{
+			client.mutate
+			(
+				|state|
+				{
+					state.pass_turn();
+
+					/* Moving declines our offer */
+					if state.draw_offer
+						== Some(copy_color(& state.color))
+					{
+						state.draw_offer = None;
+						state.message = String
+							::from("Draw offer declined");
+					}
+				}
+			);
+		},
+	}
 
 	Ok(())
 }
 
-fn client_main(mut addr: String, state_rc: Arc<Mutex<State>>)
+/* Follow the game as a spectator, the server sends a state after every
+ * move and when the game ends */
+fn watch(client: & Client)
 	-> Result<(), serde_json::Error>
 {
-	println!("Entered client_main");
+	client.mutate
+	(
+		|state| state.message = String::from("Spectating")
+	);
 
-	/* Add port number to the given address */
-	if !addr.contains(':')
+	while !client.mutate
+	(
+		|state| state.quit || state.joever != cnp::Joever::Ongoing
+	)
 	{
-		addr.push_str(":8384");
-	}
+		recv_msg(client, false)?;
 
-	{
-		let mut lock = state_rc.lock().unwrap();
-		let state = lock.deref_mut();
+		client.mutate
+		(
+			|state|
+			{
+				let turn = moves_turn(& state.board, & state.moves);
 
-		state.message = String::from("Connecting");
-	}
+				if let Some(turn) = turn
+				{
+					if turn != state.turn
+					{
+						state.pass_turn();
+					}
+				}
 
-	/* Connect */
-	let stream_result = std::net::TcpStream::connect(addr);
-	if stream_result.is_err()
-	{
-		return Ok(())
+				state.sync_clock();
+			}
+		);
 	}
-	let stream = stream_result.unwrap();
 
-	/* Create the client struct */
-	let client = Client
-	{
-		stream,
-		state_rc,
-	};
+	Ok(())
+}
 
-	client.mutate
-	(
-		|state| state.message
-		= String::from("Connected, sending handshakde")
-	);
+fn play(client: & Client)
+	-> Result<(), serde_json::Error>
+{
+	client.mutate(|state| state.status = Status::Handshake);
 
 	/* Clone the stream to the state so that the owner of the driver can
 	 * shut it down if they want to quit */
 	(
-		|stream|
+		|stream: TcpStream|
 		{
 			client.mutate
 			(
-				|state| state.stream = Some(stream)
+				|state|
+				{
+					/* The player may have quit before the
+					 * stream was stored */
+					if state.quit
+					{
+						let how = std::net::Shutdown::Both;
+						let _ = stream.shutdown(how);
+					}
+
+					state.stream = Some(stream);
+				}
 			)
 		}
-	)(client.stream.try_clone().unwrap());
+	)(client.peer.stream.try_clone().unwrap());
 
-	/* Set the player colors */
-	let (_color, opponent) = client.mutate
+	/* Send client handshake */
+	let h = client.mutate
 	(
 		|state|
+		ClientHandshake
 		{
-			state.color = cnp::Color::White;
-
-			(
-				copy_color(& state.color),
-				inv_color(& state.color),
-			)
+			server_color	: inv_color(& state.color),
+			spectate	: state.spectator,
+			token		: Some(state.token.clone()),
+			times		: true,
 		}
 	);
-
-	/* Send client handshake */
-	let h = cnp::ClientToServerHandshake
-	{
-		server_color	: opponent,
-	};
 	client.write(& h)?;
 	println!("Client handshake sent");
 
 	/* Receive server handshake */
-	let h = client.read::<cnp::ServerToClientHandshake>()?;
+	let h = client.read::<cnp::ServerToClientHandshake>(true)?;
 	println!("Server handshake received");
 
-	/* Update state with handshake data */
+	/* Update state with handshake data, the handshake does not say whose
+	 * turn it is but the moves it lists belong to that player */
 	client.mutate
 	(
 		|state|
 		{
+			if let Some(turn) = moves_turn(& h.board, & h.moves)
+			{
+				state.turn = turn;
+			}
+
+			state.clock = Clock::from_features(& h.features);
+			state.takebacks = !state.spectator
+				&& takeback::in_features(& h.features);
 			state.features = h.features;
-			state.board = h.board;
-			state.moves = h.moves;
+
+			/* The game need not have started from the standard
+			 * position, only a handshake that resumes it can tell
+			 * what was played in the meantime */
+			if !state.joined
+			{
+				state.board = h.board;
+			}
+
+			state.set_board(& h.board, & h.moves);
 			state.joever = h.joever;
+			state.joined = true;
+
+			/* The server starts the clock when it has sent the
+			 * handshake */
+			if let Some(ref mut clock) = state.clock
+			{
+				if state.joever == cnp::Joever::Ongoing
+				{
+					clock.start(& state.turn);
+				}
+			}
 		}
 	);
 
-	client.mutate(|state| state.message = String::new());
+	/* Let a server that understands takebacks know that we do too */
+	if client.mutate(|state| state.takebacks)
+	{
+		client.write(& Takeback::Supported)?;
+		println!("Takeback support sent");
+	}
+
+	client.mutate(|state| state.status = Status::Connected);
+
+	if client.mutate(|state| state.spectator)
+	{
+		watch(client)?;
+	}
 
-	/* Move loop */
-	while !client.mutate(|state| state.quit)
+	/* Move loop, runs until the game is over */
+	while !client.mutate
+	(
+		|state| state.quit || state.joever != cnp::Joever::Ongoing
+	)
 	{
 		if client.mutate(|state| state.turn == state.color)
 		{
-			send_move(& client)?;
+			send_move(client)?;
 		}
 		else
 		{
-			recv_move(& client)?;
+			recv_move(client)?;
 		}
+
+		/* The turn has passed by now if a move was made */
+		client.mutate(|state| state.sync_clock());
 	}
 
+	client.mutate
+	(
+		|state|
+		{
+			if let Some(ref mut clock) = state.clock
+			{
+				clock.stop();
+			}
+
+			state.status = Status::Closed;
+		}
+	);
+
 	Ok(())
 }
 
+/* Try to connect again after the connection was lost, waiting a little
+ * longer after each failed attempt */
+fn reconnect(addr: & str, state_rc: & Arc<Mutex<State>>)
+	-> Option<TcpStream>
+{
+	let quit = || state_rc.lock().unwrap().quit;
+	let mut delay = Duration::from_millis(250);
+
+	for _ in 0..RECONNECT_ATTEMPTS
+	{
+		if quit()
+		{
+			return None;
+		}
+
+		if let Ok(Some(stream)) = net::connect(addr, quit)
+		{
+			return Some(stream);
+		}
+
+		/* Sleep in small steps to notice if the player quits */
+		let start = std::time::Instant::now();
+		while start.elapsed() < delay && !quit()
+		{
+			std::thread::sleep(Duration::from_millis(50));
+		}
+
+		delay = std::cmp::min(delay * 2, RECONNECT_DELAY_MAX);
+	}
+
+	None
+}
+
+fn client_main(addr: String,
+		color: Option<cnp::Color>,
+		timeout: Option<Duration>,
+		spectate: bool,
+		state_rc: Arc<Mutex<State>>)
+	-> Result<(), serde_json::Error>
+{
+	println!("Entered client_main");
+
+	/* Connect, giving up if the player quits */
+	let stream_result = net::connect
+	(
+		& addr,
+		|| state_rc.lock().unwrap().quit,
+	);
+	if let Err(e) = stream_result
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if !state.quit
+		{
+			state.status = Status::ConnectFailed(e.to_string());
+		}
+
+		return Ok(())
+	}
+	let mut stream = match stream_result.unwrap()
+	{
+		Some(stream) => stream,
+		None => return Ok(()),
+	};
+
+	/* Set the player colors, picking one at random if no color was
+	 * requested */
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.color = color.unwrap_or_else(random_color);
+		state.spectator = spectate;
+	}
+
+	loop
+	{
+		/* Create the client struct */
+		let client = Client
+		{
+			peer		: net::Peer::new(stream, timeout)
+				.map_err(serde_json::Error::io)?,
+			state_rc	: state_rc.clone(),
+		};
+
+		let result = play(& client);
+
+		/* If the connection is lost during the game, reconnect and
+		 * let the handshake bring the state up to date. A server that
+		 * turned us away before its handshake will do so again. */
+		if let Err(e) = result
+		{
+			let resume = client.mutate
+			(
+				|state|
+				{
+					let resume = !state.quit
+						&& state.joined
+						&& state.joever
+						== cnp::Joever::Ongoing;
+
+					if resume
+					{
+						let s = Status::from_error(& e);
+
+						state.status = Status::Reconnecting
+							(s.describe());
+						state.stream = None;
+						state.responding = true;
+						state.next_move = None;
+						state.offer_draw = false;
+						state.draw_offer = None;
+						state.ask_takeback = false;
+						state.takeback = None;
+						state.answer = None;
+
+						if let Some(ref mut clock) = state.clock
+						{
+							clock.stop();
+						}
+					}
+
+					resume
+				}
+			);
+
+			if !resume
+			{
+				return Err(e);
+			}
+
+			println!("Connection lost, reconnecting");
+			match reconnect(& addr, & state_rc)
+			{
+				Some(s) => stream = s,
+				None => return Err(e),
+			}
+		}
+		else
+		{
+			return Ok(());
+		}
+	}
+}
+
+fn client_thread(addr: String,
+		color: Option<cnp::Color>,
+		timeout: Option<Duration>,
+		spectate: bool,
+		state_rc: Arc<Mutex<State>>)
+	-> Result<(), serde_json::Error>
+{
+	let result = client_main
+	(
+		addr,
+		color,
+		timeout,
+		spectate,
+		state_rc.clone(),
+	);
+
+	/* Report the error that ended the game, unless it was caused by the
+	 * player quitting */
+	if let Err(ref e) = result
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if !state.quit
+		{
+			state.status = Status::from_error(e);
+		}
+	}
+
+	result
+}
+
 impl ClientDriver
 {
-	pub fn new(addr: String) -> Self
+	pub fn new(addr: String,
+			color: Option<cnp::Color>,
+			timeout: Option<Duration>,
+			spectate: bool)
+		-> Self
 	{
 		/* Create an empty state wrapped in an Arc Mutex */
 		let state_rc = Arc::new(Mutex::new(State::new()));
@@ -419,7 +1345,14 @@ impl ClientDriver
 			{
 				std::thread::spawn
 				(
-					|| client_main(addr, state_rc)
+					move || client_thread
+					(
+						addr,
+						color,
+						timeout,
+						spectate,
+						state_rc,
+					)
 				)
 			}
 		)(state_rc.clone());
@@ -0,0 +1,271 @@
+extern crate serde;
+
+use self::serde::{Deserialize, Serialize};
+use crate::util::*;
+use std::time::{Duration, Instant};
+
+/* Clocks are announced to clients as an extra protocol feature that starts
+ * with this word, followed by the time control and the time left in
+ * milliseconds */
+const FEATURE: & str = "clock";
+
+/* The message of the clock extension, with the time left for each player
+ * in milliseconds. A server sends it ahead of every state to the clients
+ * that ask for it in their handshake, so that their clocks do not drift
+ * from its own. */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ClockSync
+{
+	Times
+	{
+		white	: u64,
+		black	: u64,
+	},
+}
+
+/* A message that may be the one of the clock extension */
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Timed<T>
+{
+	Clock(ClockSync),
+	Message(T),
+}
+
+/* How much time the players get, the increment is added after every move
+ * and the delay is waited out before the clock starts counting down */
+#[derive(Clone, Copy)]
+pub struct TimeControl
+{
+	pub base	: Duration,
+	pub increment	: Duration,
+	pub delay	: Duration,
+}
+
+/* A chess clock with one countdown for each player, of which at most one
+ * runs at a time */
+#[derive(Clone, Copy)]
+pub struct Clock
+{
+	pub control	: TimeControl,
+	left		: [Duration; 2],
+	running		: Option<(usize, Instant)>,
+}
+
+fn index(c: & cnp::Color) -> usize
+{
+	match c
+	{
+		cnp::Color::White => 0,
+		cnp::Color::Black => 1,
+	}
+}
+
+impl Clock
+{
+	pub fn new(control: TimeControl) -> Self
+	{
+		Self
+		{
+			control,
+			left		: [control.base; 2],
+			running		: None,
+		}
+	}
+
+	/* The time that has counted down since a clock was started */
+	fn used(self: & Self, since: Instant) -> Duration
+	{
+		since.elapsed().saturating_sub(self.control.delay)
+	}
+
+	/* Start the clock of a player, stopping the other one */
+	pub fn start(self: & mut Self, c: & cnp::Color)
+	{
+		self.stop();
+		self.running = Some((index(c), Instant::now()));
+	}
+
+	pub fn stop(self: & mut Self)
+	{
+		if let Some((i, since)) = self.running.take()
+		{
+			self.left[i] = self.left[i].saturating_sub(self.used(since));
+		}
+	}
+
+	/* Stop the running clock at the end of a move and start the other
+	 * one */
+	pub fn press(self: & mut Self)
+	{
+		if let Some((i, _)) = self.running
+		{
+			self.stop();
+			self.left[i] += self.control.increment;
+			self.running = Some((1 - i, Instant::now()));
+		}
+	}
+
+	pub fn left(self: & Self, c: & cnp::Color) -> Duration
+	{
+		let i = index(c);
+
+		match self.running
+		{
+			Some((r, since)) if r == i =>
+			{
+				self.left[i].saturating_sub(self.used(since))
+			},
+			_ => self.left[i],
+		}
+	}
+
+	pub fn running(self: & Self) -> Option<cnp::Color>
+	{
+		match self.running
+		{
+			Some((0, _)) => Some(cnp::Color::White),
+			Some(_) => Some(cnp::Color::Black),
+			None => None,
+		}
+	}
+
+	/* The player whose time has run out, if any */
+	pub fn flagged(self: & Self) -> Option<cnp::Color>
+	{
+		let c = self.running()?;
+
+		if self.left(& c).is_zero()
+		{
+			Some(c)
+		}
+		else
+		{
+			None
+		}
+	}
+
+	pub fn to_sync(self: & Self) -> ClockSync
+	{
+		ClockSync::Times
+		{
+			white	: self.left(& cnp::Color::White).as_millis() as u64,
+			black	: self.left(& cnp::Color::Black).as_millis() as u64,
+		}
+	}
+
+	/* Take the time left from the server, the running clock counts down
+	 * from there */
+	pub fn sync(self: & mut Self, s: & ClockSync)
+	{
+		let ClockSync::Times { white, black } = * s;
+
+		self.left =
+		[
+			Duration::from_millis(white),
+			Duration::from_millis(black),
+		];
+
+		if let Some((i, _)) = self.running
+		{
+			self.running = Some((i, Instant::now()));
+		}
+	}
+
+	pub fn to_feature(self: & Self) -> cnp::Features
+	{
+		cnp::Features::Other(format!
+		(
+			"{} base={} increment={} delay={} white={} black={}",
+			FEATURE,
+			self.control.base.as_millis(),
+			self.control.increment.as_millis(),
+			self.control.delay.as_millis(),
+			self.left(& cnp::Color::White).as_millis(),
+			self.left(& cnp::Color::Black).as_millis(),
+		))
+	}
+
+	/* Set up a stopped clock from the features of a server that has
+	 * one */
+	pub fn from_features(features: & [cnp::Features]) -> Option<Self>
+	{
+		features.iter().find_map
+		(
+			|f|
+			match f
+			{
+				cnp::Features::Other(s) => Self::parse_feature(s),
+				_ => None,
+			}
+		)
+	}
+
+	fn parse_feature(s: & str) -> Option<Self>
+	{
+		let mut words = s.split_whitespace();
+
+		if words.next() != Some(FEATURE)
+		{
+			return None;
+		}
+
+		let mut ms = [None; 5];
+
+		for w in words
+		{
+			let (key, value) = w.split_once('=')?;
+			let i = match key
+			{
+				"base"		=> 0,
+				"increment"	=> 1,
+				"delay"		=> 2,
+				"white"		=> 3,
+				"black"		=> 4,
+				_		=> continue,
+			};
+
+			ms[i] = Some(Duration::from_millis(value.parse().ok()?));
+		}
+
+		Some(Self
+		{
+			control		: TimeControl
+			{
+				base		: ms[0]?,
+				increment	: ms[1]?,
+				delay		: ms[2]?,
+			},
+			left		: [ms[3]?, ms[4]?],
+			running		: None,
+		})
+	}
+}
+
+/* Format the time left on a clock, with tenths of seconds when it is
+ * running low */
+pub fn format_time(d: Duration) -> String
+{
+	let secs = d.as_secs();
+
+	if secs < 10
+	{
+		format!("0:{:02}.{}", secs, d.subsec_millis() / 100)
+	}
+	else
+	{
+		format!("{}:{:02}", secs / 60, secs % 60)
+	}
+}
+
+/* The result of a player running out of time */
+pub fn flag_result(c: & cnp::Color) -> (cnp::Joever, & 'static str)
+{
+	let reason = match c
+	{
+		cnp::Color::White => "White lost on time",
+		cnp::Color::Black => "Black lost on time",
+	};
+
+	(color_win(& inv_color(c)), reason)
+}
@@ -1,13 +1,179 @@
+use crate::clock::Clock;
+use crate::fen::Fen;
+
+#[derive(Clone, PartialEq)]
+pub enum Status
+{
+	/* Connecting to, or getting ready to accept, the opponent */
+	Connecting,
+	/* Waiting for an opponent to connect */
+	Listening,
+	/* Connected, exchanging handshakes */
+	Handshake,
+	/* Connected and playing */
+	Connected,
+	/* The game is over and the connection has been closed */
+	Closed,
+	/* Could not listen on the requested address */
+	BindFailed(String),
+	/* Could not connect to, or accept, the opponent */
+	ConnectFailed(String),
+	/* The opponent sent something that does not follow the protocol */
+	ProtocolError(String),
+	/* The connection to the opponent was lost */
+	Disconnected(String),
+	/* The connection was lost and is being reestablished */
+	Reconnecting(String),
+}
+
+impl Status
+{
+	/* Classify an error that ended a driver's network thread */
+	pub fn from_error(e: & serde_json::Error) -> Self
+	{
+		match e.classify()
+		{
+			serde_json::error::Category::Io =>
+				Status::Disconnected(e.to_string()),
+			serde_json::error::Category::Eof =>
+				Status::Disconnected
+				(
+					String::from("connection closed")
+				),
+			serde_json::error::Category::Syntax
+				| serde_json::error::Category::Data =>
+				Status::ProtocolError(e.to_string()),
+		}
+	}
+
+	pub fn is_error(self: & Self) -> bool
+	{
+		match self
+		{
+			Status::BindFailed(_)
+				| Status::ConnectFailed(_)
+				| Status::ProtocolError(_)
+				| Status::Disconnected(_)
+				| Status::Reconnecting(_) => true,
+			_ => false,
+		}
+	}
+
+	pub fn describe(self: & Self) -> String
+	{
+		match self
+		{
+			Status::Connecting =>
+				String::from("Connecting"),
+			Status::Listening =>
+				String::from("Waiting for opponent"),
+			Status::Handshake =>
+				String::from("Connected, waiting for handshake"),
+			Status::Connected =>
+				String::from("Connected"),
+			Status::Closed =>
+				String::from("Connection closed"),
+			Status::BindFailed(e) =>
+				format!("Could not listen for opponents: {}", e),
+			Status::ConnectFailed(e) =>
+				format!("Could not connect: {}", e),
+			Status::ProtocolError(e) =>
+				format!("Opponent broke the protocol: {}", e),
+			Status::Disconnected(e) =>
+				format!("Opponent disconnected: {}", e),
+			Status::Reconnecting(e) =>
+				format!("{}, reconnecting", e),
+		}
+	}
+}
+
+/* Where to go in a game that is being replayed */
+pub enum Seek
+{
+	First,
+	Previous,
+	Next,
+	Last,
+	PreviousGame,
+	NextGame,
+}
+
 pub trait GameDriver
 {
 	fn features(self: & Self) -> Vec<cnp::Features>;
 	fn board(self: & Self) -> [[cnp::Piece; 8]; 8];
 	fn joever(self: & Self) -> cnp::Joever;
+	/* The color of the player at this end, who makes the moves */
 	fn color(self: & Self) -> cnp::Color;
 	fn turn(self: & Self) -> cnp::Color;
+
+	/* The color whose side of the board is drawn at the bottom */
+	fn perspective(self: & Self) -> cnp::Color
+	{
+		self.color()
+	}
+
 	fn moves(self: & Self) -> Vec<cnp::Move>;
 	fn get_next_move(self: & Self) -> Option<cnp::Move>;
 	fn set_next_move(self: & Self, m: Option<cnp::Move>);
+	fn resign(self: & Self);
+	fn offer_draw(self: & Self);
+	fn decline_draw(self: & Self);
+	fn draw_offer(self: & Self) -> Option<cnp::Color>;
+	fn clock(self: & Self) -> Option<Clock>;
+	/* The moves played so far, in standard algebraic notation */
+	fn history(self: & Self) -> Vec<String>;
+	/* The moves played so far, as far as they are known */
+	fn played(self: & Self) -> Vec<cnp::Move>;
+
+	/* The position in Forsyth-Edwards Notation. The castling rights and
+	 * the move counters follow from the moves played when they are all
+	 * known, otherwise the castling rights are worked out from the board
+	 * and the move clock is not known. */
+	fn fen(self: & Self) -> String
+	{
+		let mut fen = Fen::from_board
+			(& self.board(), & self.turn(), & self.moves());
+
+		if !fen.follow(& self.played())
+		{
+			fen.fullmoves = self.history().len() as u32 / 2 + 1;
+		}
+
+		fen.to_string()
+	}
+
+	/* Step through a game that is being replayed, the drivers that play
+	 * games ignore this */
+	fn seek(self: & Self, _to: Seek)
+	{
+	}
+
+	/* Take back the last move, or ask the opponent to when there is one
+	 * at the other end of a connection */
+	fn undo(self: & Self)
+	{
+	}
+
+	/* Play a move that was taken back again */
+	fn redo(self: & Self)
+	{
+	}
+
+	/* The color of the player who asks to take back a move, while the
+	 * request is waiting for an answer */
+	fn takeback_request(self: & Self) -> Option<cnp::Color>
+	{
+		None
+	}
+
+	/* Answer the opponent's request to take back a move */
+	fn answer_takeback(self: & Self, _accept: bool)
+	{
+	}
+
+	fn spectating(self: & Self) -> bool;
+	fn status(self: & Self) -> Status;
 	fn message(self: & Self) -> String;
 	fn quit(self: & mut Self);
 }
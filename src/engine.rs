@@ -0,0 +1,523 @@
+extern crate scrappy_chess;
+
+use self::scrappy_chess::chess;
+use crate::clock::Clock;
+use crate::driver::{GameDriver, Status};
+use crate::game::GameState;
+use crate::search::{Limits, search};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::util::*;
+
+/* How often the engine thread checks if it is its turn */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/* How far behind the engine has to think it is to accept a draw */
+pub const DRAW_MARGIN: i32 = 50;
+
+/* A move chosen by an engine, along with how good it thinks its position
+ * is in centipawns if it says */
+pub struct Thought
+{
+	pub best	: cnp::Move,
+	pub score	: Option<i32>,
+}
+
+/* Something that picks the moves of the computer's side */
+pub trait Engine: Send
+{
+	/* Pick a move for the player to move in the position that the moves
+	 * lead to from the start of the game, giving up with None if stop
+	 * returns true */
+	fn think(self: & mut Self,
+			c_state: & chess::ChessState,
+			turn: & cnp::Color,
+			played: & [cnp::Move],
+			stop: & dyn Fn() -> bool)
+		-> Result<Option<Thought>, String>;
+}
+
+/* The engine that comes with the game */
+pub struct BuiltinEngine
+{
+	pub limits	: Limits,
+}
+
+impl Engine for BuiltinEngine
+{
+	fn think(self: & mut Self,
+			c_state: & chess::ChessState,
+			turn: & cnp::Color,
+			_played: & [cnp::Move],
+			stop: & dyn Fn() -> bool)
+		-> Result<Option<Thought>, String>
+	{
+		let result = match search(c_state, turn, & self.limits, stop)
+		{
+			Some(result) => result,
+			None => return Ok(None),
+		};
+
+		Ok(Some(Thought
+		{
+			best		: translate_move
+				(& translate_board(c_state), & result.best),
+			score		: Some(result.score),
+		}))
+	}
+}
+
+struct State
+{
+	game		: GameState,
+
+	color		: cnp::Color,
+	offer_draw	: bool,
+	draw_offer	: Option<cnp::Color>,
+	status		: Status,
+	message		: String,
+	quit		: bool,
+}
+
+/* A game against the computer, which searches for its moves on a separate
+ * thread while the player's moves are played as soon as they are made */
+pub struct EngineDriver
+{
+	thread		: Option<JoinHandle<()>>,
+	state_rc	: Arc<Mutex<State>>,
+}
+
+impl GameDriver for EngineDriver
+{
+	fn features(self: & Self) -> Vec<cnp::Features>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.features()
+	}
+
+	fn board(self: & Self) -> [[cnp::Piece; 8]; 8]
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.board
+	}
+
+	fn joever(self: & Self) -> cnp::Joever
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.joever
+	}
+
+	fn color(self: & Self) -> cnp::Color
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		copy_color(& state.color)
+	}
+
+	fn turn(self: & Self) -> cnp::Color
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		copy_color(& state.game.turn)
+	}
+
+	fn moves(self: & Self) -> Vec<cnp::Move>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.moves.clone()
+	}
+
+	fn get_next_move(self: & Self) -> Option<cnp::Move>
+	{
+		None
+	}
+
+	fn set_next_move(self: & Self, m: Option<cnp::Move>)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		let m = match m
+		{
+			Some(m) if state.game.joever == cnp::Joever::Ongoing
+				&& state.game.turn == state.color => m,
+			_ => return,
+		};
+
+		let m = match state.game.find_move(& m)
+		{
+			Some(m) => m,
+			None => return,
+		};
+
+		state.game.play(m);
+		state.message = String::new();
+
+		/* The offer goes along with the move, the engine answers it
+		 * when it has thought about its own */
+		if state.offer_draw && state.game.joever == cnp::Joever::Ongoing
+		{
+			state.draw_offer = Some(copy_color(& state.color));
+			state.message = String::from("Draw offered");
+		}
+
+		state.offer_draw = false;
+
+		if let Some(reason) = state.game.reason
+		{
+			state.message = String::from(reason);
+		}
+	}
+
+	fn resign(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.game.joever == cnp::Joever::Ongoing
+		{
+			state.game.joever = color_win(& inv_color(& state.color));
+			state.message = String::from("You resigned");
+		}
+	}
+
+	fn offer_draw(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.game.joever == cnp::Joever::Ongoing
+			&& state.draw_offer.is_none()
+		{
+			state.offer_draw = true;
+			state.message = String::from
+				("A draw will be offered with your next move");
+		}
+	}
+
+	/* The engine never offers a draw, so there is nothing to decline */
+	fn decline_draw(self: & Self)
+	{
+	}
+
+	fn draw_offer(self: & Self) -> Option<cnp::Color>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.draw_offer.as_ref().map(copy_color)
+	}
+
+	fn clock(self: & Self) -> Option<Clock>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.clock
+	}
+
+	fn history(self: & Self) -> Vec<String>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.san.clone()
+	}
+
+	fn played(self: & Self) -> Vec<cnp::Move>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.played.clone()
+	}
+
+	fn fen(self: & Self) -> String
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.fen().to_string()
+	}
+
+	/* Take back the engine's answer along with the player's move, so that
+	 * it is the player's turn again */
+	fn undo(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		let mut undone = false;
+
+		while state.game.undo()
+		{
+			undone = true;
+
+			if state.game.turn == state.color
+			{
+				break;
+			}
+		}
+
+		if undone
+		{
+			state.offer_draw = false;
+			state.draw_offer = None;
+			state.message = String::from("Move taken back");
+		}
+	}
+
+	/* Play the moves that were taken back again up to the player's next
+	 * turn, the engine does not think again about its own */
+	fn redo(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		let mut redone = false;
+
+		while state.game.joever == cnp::Joever::Ongoing
+			&& state.game.redo()
+		{
+			redone = true;
+
+			if state.game.turn == state.color
+			{
+				break;
+			}
+		}
+
+		if redone
+		{
+			state.offer_draw = false;
+			state.draw_offer = None;
+			state.message = String::from
+				(state.game.reason.unwrap_or(""));
+		}
+	}
+
+	fn spectating(self: & Self) -> bool
+	{
+		false
+	}
+
+	fn status(self: & Self) -> Status
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.status.clone()
+	}
+
+	fn message(self: & Self) -> String
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.message.is_empty()
+			&& state.game.joever == cnp::Joever::Ongoing
+			&& state.game.turn != state.color
+		{
+			String::from("The computer is thinking")
+		}
+		else
+		{
+			state.message.clone()
+		}
+	}
+
+	fn quit(self: & mut Self)
+	{
+		self.state_rc.lock().unwrap().quit = true;
+
+		if self.thread.is_some()
+		{
+			let _ = self.thread.take().unwrap().join();
+		}
+	}
+}
+
+/* Think about a move whenever it is the engine's turn, until the player
+ * quits or the engine fails */
+fn engine_main(mut engine: Box<dyn Engine>, state_rc: Arc<Mutex<State>>)
+{
+	loop
+	{
+		let job =
+		{
+			let mut lock = state_rc.lock().unwrap();
+			let state = lock.deref_mut();
+
+			if state.quit
+			{
+				return;
+			}
+
+			if state.game.joever == cnp::Joever::Ongoing
+				&& state.game.turn != state.color
+			{
+				/* The search needs the rules engine, which only
+				 * plays from the standard position */
+				match state.game.chess_state()
+				{
+					Some(c_state) => Some
+					((
+						c_state,
+						copy_color(& state.game.turn),
+						state.game.played.clone(),
+					)),
+					None =>
+					{
+						state.status = Status::ProtocolError
+						(
+							String::from("the computer can \
+							only play from the standard \
+							position")
+						);
+
+						return;
+					},
+				}
+			}
+			else
+			{
+				None
+			}
+		};
+
+		let (c_state, turn, played) = match job
+		{
+			Some(job) => job,
+			None =>
+			{
+				std::thread::sleep(POLL_INTERVAL);
+				continue;
+			},
+		};
+
+		/* The state is not locked during the search, so the window
+		 * keeps responding */
+		let result = engine.think
+		(
+			& c_state,
+			& turn,
+			& played,
+			& ||
+			{
+				let state = state_rc.lock().unwrap();
+
+				state.quit
+					|| state.game.joever != cnp::Joever::Ongoing
+					|| state.game.played != played
+			},
+		);
+
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		/* The player may have resigned or taken moves back in the
+		 * meantime */
+		let thought = match result
+		{
+			Ok(Some(thought)) if !state.quit
+				&& state.game.joever == cnp::Joever::Ongoing
+				&& state.game.played == played
+				=> thought,
+			Ok(_) => continue,
+			Err(e) =>
+			{
+				state.status = Status::Disconnected(e);
+
+				return;
+			},
+		};
+
+		/* Only legal moves are played, whatever the engine says */
+		let m = match state.game.find_move(& thought.best)
+		{
+			Some(m) => m,
+			None =>
+			{
+				state.status = Status::ProtocolError(String::from
+					("the engine made an illegal move"));
+
+				return;
+			},
+		};
+
+		state.message = String::new();
+
+		/* Take the player's offer if the engine is worse off */
+		if state.draw_offer == Some(copy_color(& state.color))
+		{
+			state.draw_offer = None;
+
+			if thought.score.map_or(false, |s| s < -DRAW_MARGIN)
+			{
+				state.game.joever = cnp::Joever::Draw;
+				state.message = String::from("Draw agreed");
+
+				continue;
+			}
+
+			state.message = String::from("Draw offer declined");
+		}
+
+		state.game.play(m);
+
+		if let Some(reason) = state.game.reason
+		{
+			state.message = String::from(reason);
+		}
+	}
+}
+
+impl EngineDriver
+{
+	pub fn new(color: Option<cnp::Color>, engine: Box<dyn Engine>) -> Self
+	{
+		/* Create the state wrapped in an Arc Mutex, picking a color at
+		 * random if none was asked for */
+		let state_rc = Arc::new(Mutex::new(State
+		{
+			game		: GameState::new(),
+
+			color		: color.unwrap_or_else(random_color),
+			offer_draw	: false,
+			draw_offer	: None,
+			status		: Status::Connected,
+			message		: String::new(),
+			quit		: false,
+		}));
+
+		/* Spin up the engine thread and pass the state to it */
+		let thread =
+		(
+			|state_rc|
+			{
+				std::thread::spawn
+				(
+					move || engine_main(engine, state_rc)
+				)
+			}
+		)(state_rc.clone());
+
+		/* Create the driver handle and return it */
+		Self
+		{
+			thread		: Some(thread),
+			state_rc,
+		}
+	}
+}
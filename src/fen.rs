@@ -0,0 +1,612 @@
+use crate::rules::lose_castling;
+use crate::util::*;
+use std::fmt;
+
+/* The name of a square in algebraic notation, such as e4 */
+pub fn square_name(x: usize, y: usize) -> String
+{
+	format!("{}{}", (b'a' + x as u8) as char, 8 - y)
+}
+
+fn placement(board: & [[cnp::Piece; 8]; 8]) -> String
+{
+	let mut s = String::new();
+
+	for y in 0..8
+	{
+		let mut empty = 0;
+
+		for x in 0..8
+		{
+			if board[y][x] == cnp::Piece::None
+			{
+				empty += 1;
+				continue;
+			}
+
+			if empty > 0
+			{
+				s.push_str(& empty.to_string());
+				empty = 0;
+			}
+
+			s.push(piece_to_char(& board[y][x]));
+		}
+
+		if empty > 0
+		{
+			s.push_str(& empty.to_string());
+		}
+
+		if y < 7
+		{
+			s.push('/');
+		}
+	}
+
+	s
+}
+
+/* Castling rights as far as the board tells, a king and rook that stand
+ * where they started are taken to not have moved */
+fn castling(board: & [[cnp::Piece; 8]; 8]) -> String
+{
+	let mut s = String::new();
+
+	for (y, king, rook, rights) in
+	[
+		(7, cnp::Piece::WhiteKing, cnp::Piece::WhiteRook, ['K', 'Q']),
+		(0, cnp::Piece::BlackKing, cnp::Piece::BlackRook, ['k', 'q']),
+	]
+	{
+		if board[y][4] != king
+		{
+			continue;
+		}
+
+		if board[y][7] == rook
+		{
+			s.push(rights[0]);
+		}
+
+		if board[y][0] == rook
+		{
+			s.push(rights[1]);
+		}
+	}
+
+	if s.is_empty()
+	{
+		s.push('-');
+	}
+
+	s
+}
+
+/* The square that a pawn can be taken en passant on, which is where a
+ * legal pawn capture lands on an empty square */
+fn en_passant(board: & [[cnp::Piece; 8]; 8], moves: & [cnp::Move])
+	-> Option<(usize, usize)>
+{
+	let ep = moves.iter().find
+	(
+		|m|
+		piece_is_kind(& board[m.start_y][m.start_x], PieceKind::Pawn)
+			&& m.start_x != m.end_x
+			&& board[m.end_y][m.end_x] == cnp::Piece::None
+	);
+
+	ep.map(|m| (m.end_x, m.end_y))
+}
+
+/* A position read from FEN */
+pub struct Fen
+{
+	pub board	: [[cnp::Piece; 8]; 8],
+	pub turn	: cnp::Color,
+	/* The castling rights in the order KQkq, - for none */
+	pub castling	: String,
+	pub en_passant	: Option<(usize, usize)>,
+	pub halfmoves	: u32,
+	pub fullmoves	: u32,
+}
+
+impl Fen
+{
+	/* A position with the castling and en passant rights worked out from
+	 * the board and the legal moves, and the move counters of the start
+	 * of a game */
+	pub fn from_board(board: & [[cnp::Piece; 8]; 8],
+			turn: & cnp::Color,
+			moves: & [cnp::Move])
+		-> Self
+	{
+		Self
+		{
+			board		: * board,
+			turn		: copy_color(turn),
+			castling	: castling(board),
+			en_passant	: en_passant(board, moves),
+			halfmoves	: 0,
+			fullmoves	: 1,
+		}
+	}
+
+	/* Work out the castling rights and the move counters from the moves
+	 * that were played from the standard position. Returns false and
+	 * leaves the position as it is if the moves do not lead to it, as
+	 * when the game started from some other position. */
+	pub fn follow(self: & mut Self, played: & [cnp::Move]) -> bool
+	{
+		let mut board = default_board();
+		let mut castling = String::from("KQkq");
+		let mut halfmoves = 0;
+
+		for m in played
+		{
+			/* Captures and pawn moves reset the move clock */
+			if piece_is_kind(& board[m.start_y][m.start_x], PieceKind::Pawn)
+				|| board[m.end_y][m.end_x] != cnp::Piece::None
+			{
+				halfmoves = 0;
+			}
+			else
+			{
+				halfmoves += 1;
+			}
+
+			lose_castling(& mut castling, m);
+			board = apply_move(& board, m);
+		}
+
+		/* White moves first, so the number of moves tells whose
+		 * turn it is */
+		let turn = if played.len() % 2 == 0
+		{
+			cnp::Color::White
+		}
+		else
+		{
+			cnp::Color::Black
+		};
+
+		if board != self.board || turn != self.turn
+		{
+			return false;
+		}
+
+		self.castling = castling;
+		self.halfmoves = halfmoves;
+		self.fullmoves = played.len() as u32 / 2 + 1;
+
+		true
+	}
+
+	/* The standard starting position, whatever the move counters say */
+	pub fn is_standard(self: & Self) -> bool
+	{
+		self.board == default_board()
+			&& self.turn == cnp::Color::White
+			&& self.castling == "KQkq"
+			&& self.en_passant.is_none()
+	}
+}
+
+impl fmt::Display for Fen
+{
+	fn fmt(self: & Self, f: & mut fmt::Formatter) -> fmt::Result
+	{
+		let turn = match self.turn
+		{
+			cnp::Color::White => 'w',
+			cnp::Color::Black => 'b',
+		};
+		let en_passant = match self.en_passant
+		{
+			Some((x, y)) => square_name(x, y),
+			None => String::from("-"),
+		};
+
+		write!
+		(
+			f,
+			"{} {} {} {} {} {}",
+			placement(& self.board),
+			turn,
+			self.castling,
+			en_passant,
+			self.halfmoves,
+			self.fullmoves,
+		)
+	}
+}
+
+fn parse_square(s: & str) -> Option<(usize, usize)>
+{
+	match s.as_bytes()
+	{
+		[file @ b'a'..=b'h', rank @ b'1'..=b'8'] =>
+		{
+			Some(((file - b'a') as usize, (b'8' - rank) as usize))
+		},
+		_ => None,
+	}
+}
+
+fn parse_placement(s: & str) -> Result<[[cnp::Piece; 8]; 8], String>
+{
+	let ranks: Vec<& str> = s.split('/').collect();
+	let mut board = [[cnp::Piece::None; 8]; 8];
+
+	if ranks.len() != 8
+	{
+		return Err(format!("'{}' does not have 8 ranks", s));
+	}
+
+	for (y, rank) in ranks.iter().enumerate()
+	{
+		let mut x = 0;
+
+		for c in rank.bytes()
+		{
+			if (b'1'..=b'8').contains(& c)
+			{
+				x += (c - b'0') as usize;
+			}
+			else
+			{
+				let p = char_to_piece(c);
+
+				if p == cnp::Piece::None
+				{
+					return Err(format!
+						("'{}' is not a piece", c as char));
+				}
+
+				if x < 8
+				{
+					board[y][x] = p;
+				}
+
+				x += 1;
+			}
+		}
+
+		if x != 8
+		{
+			return Err(format!
+				("rank '{}' does not have 8 squares", rank));
+		}
+	}
+
+	Ok(board)
+}
+
+/* Check that a position could come up in a game */
+fn validate(fen: & Fen) -> Result<(), String>
+{
+	for king in [cnp::Piece::WhiteKing, cnp::Piece::BlackKing]
+	{
+		let kings = fen.board
+			.iter()
+			.flatten()
+			.filter(|p| ** p == king)
+			.count();
+
+		if kings != 1
+		{
+			return Err(String::from("each side must have one king"));
+		}
+	}
+
+	for y in [0, 7]
+	{
+		if fen.board[y]
+			.iter()
+			.any(|p| piece_is_kind(p, PieceKind::Pawn))
+		{
+			return Err(String::from
+				("pawns can not stand on the first or last rank"));
+		}
+	}
+
+	if in_check(& fen.board, & inv_color(& fen.turn))
+	{
+		return Err(String::from
+			("the side that is not to move is in check"));
+	}
+
+	/* The king and rook of a castling right must not have moved */
+	let allowed = castling(& fen.board);
+	if fen.castling != "-"
+		&& !fen.castling.chars().all(|c| allowed.contains(c))
+	{
+		return Err(format!
+		(
+			"castling rights '{}' do not fit the board",
+			fen.castling,
+		));
+	}
+
+	/* A pawn that has just moved two squares stands in front of the en
+	 * passant square */
+	if let Some((x, y)) = fen.en_passant
+	{
+		let (rank, pawn, dy) = match fen.turn
+		{
+			cnp::Color::White => (2, cnp::Piece::BlackPawn, 1),
+			cnp::Color::Black => (5, cnp::Piece::WhitePawn, -1),
+		};
+		let front = (y as i32 + dy) as usize;
+
+		if y != rank
+			|| fen.board[y][x] != cnp::Piece::None
+			|| fen.board[front][x] != pawn
+		{
+			return Err(format!
+			(
+				"{} can not be an en passant square",
+				square_name(x, y),
+			));
+		}
+	}
+
+	Ok(())
+}
+
+/* Read and check a position in Forsyth-Edwards Notation, the move counters
+ * may be left out */
+pub fn parse(s: & str) -> Result<Fen, String>
+{
+	let fields: Vec<& str> = s.split_whitespace().collect();
+
+	if fields.len() != 4 && fields.len() != 6
+	{
+		return Err(String::from("a FEN has 4 or 6 fields"));
+	}
+
+	let turn = match fields[1]
+	{
+		"w" => cnp::Color::White,
+		"b" => cnp::Color::Black,
+		t => return Err(format!("'{}' is not a side to move", t)),
+	};
+
+	/* Put the castling rights in the usual order, so that they can be
+	 * compared */
+	let castling = if fields[2] == "-"
+	{
+		String::from("-")
+	}
+	else
+	{
+		let rights: String = "KQkq"
+			.chars()
+			.filter(|c| fields[2].contains(* c))
+			.collect();
+
+		if rights.is_empty() || rights.len() != fields[2].len()
+		{
+			return Err(format!
+				("'{}' are not castling rights", fields[2]));
+		}
+
+		rights
+	};
+
+	let en_passant = match fields[3]
+	{
+		"-" => None,
+		ep => Some(parse_square(ep).ok_or_else
+			(|| format!("'{}' is not a square", ep))?),
+	};
+
+	let counter = |s: & str| s.parse::<u32>()
+		.map_err(|_| format!("'{}' is not a move count", s));
+	let (halfmoves, fullmoves) = if fields.len() == 6
+	{
+		(counter(fields[4])?, counter(fields[5])?.max(1))
+	}
+	else
+	{
+		(0, 1)
+	};
+
+	let fen = Fen
+	{
+		board		: parse_placement(fields[0])?,
+		turn,
+		castling,
+		en_passant,
+		halfmoves,
+		fullmoves,
+	};
+
+	validate(& fen)?;
+
+	Ok(fen)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/* A move from one square to another, such as e2e4 */
+	fn mv(s: & str) -> cnp::Move
+	{
+		let (start_x, start_y) = parse_square(& s[..2]).unwrap();
+		let (end_x, end_y) = parse_square(& s[2..]).unwrap();
+
+		cnp::Move
+		{
+			start_x,
+			start_y,
+			end_x,
+			end_y,
+			promotion	: cnp::Piece::None,
+		}
+	}
+
+	fn play(moves: & [& str]) -> (Vec<cnp::Move>, [[cnp::Piece; 8]; 8])
+	{
+		let played: Vec<cnp::Move> = moves.iter().map(|m| mv(m)).collect();
+		let board = played
+			.iter()
+			.fold(default_board(), |board, m| apply_move(& board, m));
+
+		(played, board)
+	}
+
+	fn error(s: & str) -> String
+	{
+		parse(s).err().unwrap()
+	}
+
+	#[test]
+	fn read_and_write()
+	{
+		for s in
+		[
+			"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+			"rnbqkbnr/ppp1pppp/8/8/3pP3/8/PPPP1PPP/RNBQKBNR b Kq e3 0 3",
+			"8/8/4k3/8/8/4K3/8/8 w - - 37 80",
+		]
+		{
+			assert_eq!(parse(s).unwrap().to_string(), s);
+		}
+
+		let fen = parse("r3k3/8/8/8/8/8/8/4K2R w qK -").unwrap();
+
+		assert_eq!(fen.castling, "Kq");
+		assert_eq!(fen.halfmoves, 0);
+		assert_eq!(fen.fullmoves, 1);
+		assert!(parse
+			("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 5 9")
+			.unwrap()
+			.is_standard());
+	}
+
+	#[test]
+	fn syntax_errors()
+	{
+		assert_eq!(error("8/8/8/8/8/8/8/8 w -"), "a FEN has 4 or 6 fields");
+		assert_eq!(error("4k3/8/8/8/8/8/4K3 w - -"),
+			"'4k3/8/8/8/8/8/4K3' does not have 8 ranks");
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K2X w - -"), "'X' is not a piece");
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K4 w - -"),
+			"rank '4K4' does not have 8 squares");
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K3 x - -"),
+			"'x' is not a side to move");
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K3 w KK -"),
+			"'KK' are not castling rights");
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K3 w - e9"), "'e9' is not a square");
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K3 w - - x 1"),
+			"'x' is not a move count");
+	}
+
+	#[test]
+	fn kings()
+	{
+		let e = "each side must have one king";
+
+		assert_eq!(error("8/8/8/8/8/8/8/4K3 w - -"), e);
+		assert_eq!(error("4k3/8/8/8/8/8/8/8 w - -"), e);
+		assert_eq!(error("4k3/8/8/8/8/8/8/3KK3 w - -"), e);
+		assert_eq!(error("3kk3/8/8/8/8/8/8/4K3 w - -"), e);
+	}
+
+	#[test]
+	fn back_rank_pawns()
+	{
+		let e = "pawns can not stand on the first or last rank";
+
+		assert_eq!(error("P3k3/8/8/8/8/8/8/4K3 w - -"), e);
+		assert_eq!(error("4k3/8/8/8/8/8/8/p3K3 w - -"), e);
+	}
+
+	/* The side to move may be in check, the other side not */
+	#[test]
+	fn check()
+	{
+		assert!(parse("4k3/8/8/8/8/8/8/4K2r w - -").is_ok());
+		assert_eq!(error("4k3/8/8/8/8/8/8/4K2r b - -"),
+			"the side that is not to move is in check");
+	}
+
+	#[test]
+	fn castling_rights()
+	{
+		let board = "r3k2r/8/8/8/8/8/8/R3K2R";
+
+		assert!(parse(& format!("{} w KQkq -", board)).is_ok());
+		assert_eq!(error("r3k2r/8/8/8/8/8/8/R3K1R1 w KQkq -"),
+			"castling rights 'KQkq' do not fit the board");
+		assert_eq!(error("r3k2r/8/8/8/8/8/8/R2K3R w Qkq -"),
+			"castling rights 'Qkq' do not fit the board");
+		assert_eq!(error("1r2k2r/8/8/8/8/8/8/R3K2R w Kq -"),
+			"castling rights 'Kq' do not fit the board");
+	}
+
+	#[test]
+	fn en_passant_square()
+	{
+		assert!(parse("4k3/8/8/8/4P3/8/8/4K3 b - e3").is_ok());
+		assert!(parse("4k3/8/8/3p4/8/8/8/4K3 w - d6").is_ok());
+		/* The wrong rank for the side to move */
+		assert_eq!(error("4k3/8/8/8/4P3/8/8/4K3 w - e3"),
+			"e3 can not be an en passant square");
+		/* No pawn in front of it */
+		assert_eq!(error("4k3/8/8/8/3P4/8/8/4K3 b - e3"),
+			"e3 can not be an en passant square");
+		/* Not empty */
+		assert_eq!(error("4k3/8/8/8/4P3/4N3/8/4K3 b - e3"),
+			"e3 can not be an en passant square");
+	}
+
+	#[test]
+	fn follow_the_game()
+	{
+		/* 1. e4 Nf6 2. Nf3 Nxe4 3. Rg1 Ng5 4. Rh1 */
+		let (played, board) = play
+			(& ["e2e4", "g8f6", "g1f3", "f6e4", "h1g1", "e4g5", "g1h1"]);
+		let mut fen = Fen::from_board(& board, & cnp::Color::Black, & []);
+
+		/* The rook is back where it started, but has moved */
+		assert_eq!(fen.castling, "KQkq");
+		assert!(fen.follow(& played));
+		assert_eq!(fen.castling, "Qkq");
+		assert_eq!(fen.halfmoves, 3);
+		assert_eq!(fen.fullmoves, 4);
+
+		/* 1. e4 e5 2. Ke2 Ke7 */
+		let (played, board) = play(& ["e2e4", "e7e5", "e1e2", "e8e7"]);
+		let mut fen = Fen::from_board(& board, & cnp::Color::White, & []);
+
+		assert!(fen.follow(& played));
+		assert_eq!(fen.castling, "-");
+		assert_eq!(fen.halfmoves, 2);
+		assert_eq!(fen.fullmoves, 3);
+	}
+
+	/* Moves that do not lead to the position leave it alone */
+	#[test]
+	fn follow_another_game()
+	{
+		let (played, board) = play(& ["g1f3", "g8f6", "f3g1", "f6g8"]);
+		let mut fen = Fen::from_board(& board, & cnp::Color::Black, & []);
+
+		/* The board is right but black can not be to move */
+		assert!(!fen.follow(& played));
+
+		fen.turn = cnp::Color::White;
+		assert!(!fen.follow(& played[..2]));
+		assert_eq!(fen.halfmoves, 0);
+		assert_eq!(fen.fullmoves, 1);
+
+		assert!(fen.follow(& played));
+		assert_eq!(fen.castling, "KQkq");
+		assert_eq!(fen.halfmoves, 4);
+		assert_eq!(fen.fullmoves, 3);
+	}
+}
@@ -0,0 +1,607 @@
+extern crate scrappy_chess;
+
+use self::scrappy_chess::chess;
+use crate::clock::{Clock, flag_result};
+use crate::fen::Fen;
+use crate::rules::{en_passant_after, legal_moves, lose_castling};
+use crate::san::move_to_san;
+use crate::util::*;
+
+/* Moves without a capture or pawn move after which the game is drawn */
+const FIFTY_MOVE_LIMIT: u32 = 100;
+/* How many times a position must occur for the game to be drawn */
+const REPETITION_LIMIT: usize = 3;
+
+/* A position as far as repetition is concerned, two positions with the same
+ * pieces and the same legal moves also have the same castling and en passant
+ * rights */
+#[derive(PartialEq)]
+struct Position
+{
+	board		: [[cnp::Piece; 8]; 8],
+	turn		: cnp::Color,
+	moves		: Vec<(usize, usize, usize, usize)>,
+}
+
+/* What a move changed that can not be worked out again when it is taken
+ * back */
+struct Undo
+{
+	c_state		: Option<chess::ChessState>,
+	board		: [[cnp::Piece; 8]; 8],
+	castling	: String,
+	en_passant	: Option<(usize, usize)>,
+	halfmoves	: u32,
+	/* The positions that the move cleared from the history */
+	history		: Option<Vec<Position>>,
+}
+
+/* The authoritative state of a game, for the drivers that enforce the rules
+ * themselves */
+pub struct GameState
+{
+	/* The rules engine can only be set up in the standard position, the
+	 * moves of games that start from another one are worked out here */
+	c_state		: Option<chess::ChessState>,
+	c_moveset	: chess::MoveSet,
+	c_moves		: Vec<chess::Move>,
+
+	pub board	: [[cnp::Piece; 8]; 8],
+	pub joever	: cnp::Joever,
+	pub turn	: cnp::Color,
+	pub moves	: Vec<cnp::Move>,
+	pub reason	: Option<& 'static str>,
+	pub clock	: Option<Clock>,
+	pub played	: Vec<cnp::Move>,
+	pub san		: Vec<String>,
+
+	castling	: String,
+	en_passant	: Option<(usize, usize)>,
+	halfmoves	: u32,
+	/* The moves of both players that came before the starting position,
+	 * which the move numbers count on from */
+	start_ply	: u32,
+	history		: Vec<Position>,
+	/* One record for each move played, and the moves that have been
+	 * taken back since a move was last made */
+	undo		: Vec<Undo>,
+	undone		: Vec<cnp::Move>,
+	/* The game was ended by the last move, rather than by a resignation,
+	 * an agreed draw or the clock */
+	move_ended	: bool,
+}
+
+impl GameState
+{
+	pub fn new() -> Self
+	{
+		let c_state = chess::ChessState::standard();
+		let mut game = Self::setup
+			(Some(c_state), & translate_board(& c_state));
+
+		game.update();
+		game.history.push(game.position());
+
+		game
+	}
+
+	/* Start a game from a position in FEN, which may already be over */
+	pub fn from_fen(fen: & Fen) -> Self
+	{
+		let mut game = if fen.is_standard()
+		{
+			Self::new()
+		}
+		else
+		{
+			let mut game = Self::setup(None, & fen.board);
+
+			game.turn = copy_color(& fen.turn);
+			game.castling = fen.castling.clone();
+			game.en_passant = fen.en_passant;
+			game.update();
+			game.history.push(game.position());
+
+			game
+		};
+
+		game.halfmoves = fen.halfmoves;
+		game.start_ply = (fen.fullmoves - 1) * 2
+			+ (fen.turn == cnp::Color::Black) as u32;
+		game.check_draw();
+
+		game
+	}
+
+	fn setup(c_state: Option<chess::ChessState>,
+			board: & [[cnp::Piece; 8]; 8])
+		-> Self
+	{
+		Self
+		{
+			c_state,
+			c_moveset	: chess::MoveSet::new(),
+			c_moves		: Vec::new(),
+
+			board		: * board,
+			joever		: cnp::Joever::Ongoing,
+			turn		: cnp::Color::White,
+			moves		: Vec::new(),
+			reason		: None,
+			clock		: None,
+			played		: Vec::new(),
+			san		: Vec::new(),
+
+			castling	: String::from("KQkq"),
+			en_passant	: None,
+			halfmoves	: 0,
+			start_ply	: 0,
+			history		: Vec::new(),
+			undo		: Vec::new(),
+			undone		: Vec::new(),
+			move_ended	: false,
+		}
+	}
+
+	fn pass_turn(self: & mut Self)
+	{
+		self.turn = inv_color(& self.turn);
+	}
+
+	/* The features of the protocol that the rules support, along with
+	 * the state of the clock */
+	pub fn features(self: & Self) -> Vec<cnp::Features>
+	{
+		let mut features = vec!
+		[
+			cnp::Features::PossibleMoveGeneration,
+			cnp::Features::Stalemate,
+		];
+
+		if let Some(ref clock) = self.clock
+		{
+			features.push(clock.to_feature());
+		}
+
+		features
+	}
+
+	/* Run the clock of the player to move while the game is going */
+	pub fn start_clock(self: & mut Self)
+	{
+		if let Some(ref mut clock) = self.clock
+		{
+			if self.joever == cnp::Joever::Ongoing
+			{
+				clock.start(& self.turn);
+			}
+		}
+	}
+
+	pub fn stop_clock(self: & mut Self)
+	{
+		if let Some(ref mut clock) = self.clock
+		{
+			clock.stop();
+		}
+	}
+
+	/* End the game if the player to move has run out of time, returns
+	 * true if they have */
+	pub fn check_flag(self: & mut Self) -> bool
+	{
+		let flagged = match self.clock
+		{
+			Some(ref clock) if self.joever == cnp::Joever::Ongoing
+				=> clock.flagged(),
+			_ => None,
+		};
+
+		match flagged
+		{
+			Some(c) =>
+			{
+				let (joever, reason) = flag_result(& c);
+
+				self.joever = joever;
+				self.reason = Some(reason);
+				self.stop_clock();
+
+				true
+			},
+			None => false,
+		}
+	}
+
+	fn update(self: & mut Self)
+	{
+		match self.c_state
+		{
+			Some(c_state) =>
+			{
+				self.board = translate_board(& c_state);
+
+				self.c_moves = c_state.get_moves(& self.c_moveset);
+				self.moves = self.c_moves
+					.iter()
+					.map(|cm| translate_move(& self.board, cm))
+					.collect();
+			},
+			None =>
+			{
+				self.moves = legal_moves
+				(
+					& self.board,
+					& self.turn,
+					& self.castling,
+					self.en_passant,
+				);
+			},
+		}
+
+		/* The player to move has no moves, it is checkmate if their
+		 * king is attacked and stalemate otherwise */
+		if self.moves.len() == 0
+		{
+			if in_check(& self.board, & self.turn)
+			{
+				self.joever = color_win(& inv_color(& self.turn));
+				self.reason = Some("Checkmate");
+			}
+			else
+			{
+				self.joever = cnp::Joever::Draw;
+				self.reason = Some("Draw by stalemate");
+			}
+		}
+	}
+
+	fn position(self: & Self) -> Position
+	{
+		Position
+		{
+			board		: self.board,
+			turn		: copy_color(& self.turn),
+			moves		: self.moves
+				.iter()
+				.map(|m| (m.start_x, m.start_y, m.end_x, m.end_y))
+				.collect(),
+		}
+	}
+
+	/* End the game if one of the automatic draw rules applies */
+	fn check_draw(self: & mut Self)
+	{
+		if self.joever != cnp::Joever::Ongoing
+		{
+			return;
+		}
+
+		let position = self.position();
+		let repetitions = self.history
+			.iter()
+			.filter(|p| ** p == position)
+			.count();
+
+		if repetitions >= REPETITION_LIMIT
+		{
+			self.reason = Some("Draw by threefold repetition");
+		}
+		else if self.halfmoves >= FIFTY_MOVE_LIMIT
+		{
+			self.reason = Some("Draw by the fifty-move rule");
+		}
+		else if insufficient_material(& self.board)
+		{
+			self.reason = Some("Draw by insufficient material");
+		}
+		else
+		{
+			return;
+		}
+
+		self.joever = cnp::Joever::Draw;
+	}
+
+	/* The position in FEN */
+	pub fn fen(self: & Self) -> Fen
+	{
+		let mut fen = Fen::from_board
+			(& self.board, & self.turn, & self.moves);
+		fen.castling = self.castling.clone();
+		fen.halfmoves = self.halfmoves;
+		fen.fullmoves = (self.start_ply + self.played.len() as u32) / 2 + 1;
+
+		fen
+	}
+
+	/* The position as the rules engine sees it, for searching, which is
+	 * only there for games from the standard position */
+	pub fn chess_state(self: & Self) -> Option<chess::ChessState>
+	{
+		self.c_state
+	}
+
+	/* Look up a move among the legal moves */
+	pub fn find_move(self: & Self, m: & cnp::Move) -> Option<cnp::Move>
+	{
+		self.moves.iter().find(|n| ** n == * m).copied()
+	}
+
+	/* Play a move that was returned by find_move, the moves that were
+	 * taken back can no longer be played again */
+	pub fn play(self: & mut Self, m: cnp::Move)
+	{
+		self.undone.clear();
+		self.play_move(m);
+	}
+
+	fn play_move(self: & mut Self, m: cnp::Move)
+	{
+		let board = self.board;
+		let moves = self.moves.clone();
+		self.played.push(m);
+
+		let mut undo = Undo
+		{
+			c_state		: self.c_state,
+			board,
+			castling	: self.castling.clone(),
+			en_passant	: self.en_passant,
+			halfmoves	: self.halfmoves,
+			history		: None,
+		};
+
+		/* Captures and pawn moves can not be undone, so no earlier
+		 * position can occur again */
+		let piece = self.board[m.start_y][m.start_x];
+		let target = self.board[m.end_y][m.end_x];
+
+		if piece_is_kind(& piece, PieceKind::Pawn)
+			|| target != cnp::Piece::None
+		{
+			self.halfmoves = 0;
+			undo.history = Some(std::mem::take(& mut self.history));
+		}
+		else
+		{
+			self.halfmoves += 1;
+		}
+
+		/* The rules engine keeps up with the game as long as it is
+		 * playing it */
+		self.c_state = self.c_state
+			.and(match_move(& board, & m, & self.c_moves))
+			.map(|cm| cm.result);
+		self.board = apply_move(& board, & m);
+		self.en_passant = en_passant_after(& board, & m);
+		lose_castling(& mut self.castling, & m);
+		self.pass_turn();
+		self.update();
+		self.san.push(move_to_san(& board, & m, & moves, & self.moves));
+
+		self.history.push(self.position());
+		self.undo.push(undo);
+		self.check_draw();
+		self.move_ended = self.joever != cnp::Joever::Ongoing;
+
+		if let Some(ref mut clock) = self.clock
+		{
+			clock.press();
+
+			if self.joever != cnp::Joever::Ongoing
+			{
+				clock.stop();
+			}
+		}
+	}
+
+	/* Take back the last move, returns false if there is none or the game
+	 * was ended by something other than a move. The game goes on even if
+	 * the move ended it, and the time that was used stays used. */
+	pub fn undo(self: & mut Self) -> bool
+	{
+		if self.joever != cnp::Joever::Ongoing && !self.move_ended
+		{
+			return false;
+		}
+
+		let undo = match self.undo.pop()
+		{
+			Some(undo) => undo,
+			None => return false,
+		};
+
+		if let Some(m) = self.played.pop()
+		{
+			self.undone.push(m);
+		}
+		self.san.pop();
+
+		self.c_state = undo.c_state;
+		self.board = undo.board;
+		self.castling = undo.castling;
+		self.en_passant = undo.en_passant;
+		self.halfmoves = undo.halfmoves;
+
+		match undo.history
+		{
+			Some(history) => self.history = history,
+			None =>
+			{
+				self.history.pop();
+			},
+		}
+
+		self.joever = cnp::Joever::Ongoing;
+		self.reason = None;
+		self.move_ended = false;
+		self.pass_turn();
+		self.update();
+
+		self.stop_clock();
+		self.start_clock();
+
+		true
+	}
+
+	/* Play the last move that was taken back again, returns false if
+	 * there is none */
+	pub fn redo(self: & mut Self) -> bool
+	{
+		let m = match self.undone.last()
+		{
+			Some(m) => self.find_move(m),
+			None => None,
+		};
+
+		match m
+		{
+			Some(m) =>
+			{
+				self.undone.pop();
+				self.play_move(m);
+
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::fen;
+	use crate::san::san_to_move;
+
+	fn game(s: & str) -> GameState
+	{
+		GameState::from_fen(& fen::parse(s).unwrap())
+	}
+
+	fn play(game: & mut GameState, sans: & [& str])
+	{
+		for san in sans
+		{
+			let m = san_to_move(san, & game.board, & game.moves).unwrap();
+
+			game.play(game.find_move(& m).unwrap());
+		}
+	}
+
+	#[test]
+	fn black_moves_first()
+	{
+		let s = "r3k2r/8/8/8/4Pp2/8/8/R3K2R b KQkq e3 0 20";
+		let mut game = game(s);
+
+		assert_eq!(game.turn, cnp::Color::Black);
+		assert_eq!(game.fen().to_string(), s);
+
+		play(& mut game, & ["fxe3", "Rxh8+", "Kd7"]);
+		assert_eq!
+		(
+			game.fen().to_string(),
+			"r6R/3k4/8/8/8/4p3/8/R3K3 w Q - 1 22",
+		);
+		assert_eq!(game.san, ["fxe3", "Rxh8+", "Kd7"]);
+
+		/* Taking the moves back brings the rights back */
+		while game.undo()
+		{
+		}
+		assert_eq!(game.fen().to_string(), s);
+		assert!(game.chess_state().is_none());
+	}
+
+	#[test]
+	fn standard_fen_uses_the_rules_engine()
+	{
+		let game = game
+			("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+
+		assert!(game.chess_state().is_some());
+		assert_eq!(game.moves.len(), 20);
+	}
+
+	#[test]
+	fn over_from_the_start()
+	{
+		let game = game("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+
+		assert_eq!(game.joever, cnp::Joever::Draw);
+		assert_eq!(game.reason, Some("Draw by stalemate"));
+	}
+
+	#[test]
+	fn threefold_repetition()
+	{
+		let mut game = GameState::new();
+		let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+
+		play(& mut game, & shuffle);
+		play(& mut game, & shuffle[..3]);
+		assert_eq!(game.joever, cnp::Joever::Ongoing);
+
+		/* The starting position for the third time */
+		play(& mut game, & shuffle[3..]);
+		assert_eq!(game.joever, cnp::Joever::Draw);
+		assert_eq!(game.reason, Some("Draw by threefold repetition"));
+
+		/* A move that ended the game can be taken back */
+		assert!(game.undo());
+		assert_eq!(game.joever, cnp::Joever::Ongoing);
+		assert_eq!(game.reason, None);
+	}
+
+	/* A pawn move starts the count again, so a position from before it
+	 * does not count towards a repetition */
+	#[test]
+	fn repetition_after_a_pawn_move()
+	{
+		let mut game = GameState::new();
+		let shuffle = ["Nf3", "Nf6", "Ng1", "Ng8"];
+
+		play(& mut game, & shuffle);
+		play(& mut game, & ["e3", "e6"]);
+		play(& mut game, & shuffle);
+		assert_eq!(game.joever, cnp::Joever::Ongoing);
+
+		play(& mut game, & shuffle);
+		assert_eq!(game.reason, Some("Draw by threefold repetition"));
+	}
+
+	#[test]
+	fn fifty_move_rule()
+	{
+		let s = "4k3/8/8/8/8/8/P7/R3K3 w - - 98 80";
+
+		let mut drawn = game(s);
+		play(& mut drawn, & ["Rb1"]);
+		assert_eq!(drawn.joever, cnp::Joever::Ongoing);
+		play(& mut drawn, & ["Kd7"]);
+		assert_eq!(drawn.joever, cnp::Joever::Draw);
+		assert_eq!(drawn.reason, Some("Draw by the fifty-move rule"));
+
+		/* A pawn move starts the count again */
+		let mut reset = game(s);
+		play(& mut reset, & ["a3", "Kd7"]);
+		assert_eq!(reset.joever, cnp::Joever::Ongoing);
+		assert_eq!(reset.halfmoves, 1);
+	}
+
+	#[test]
+	fn insufficient_material()
+	{
+		let mut game = game("4k3/8/8/8/8/8/3r4/4KB2 w - - 0 1");
+
+		assert_eq!(game.joever, cnp::Joever::Ongoing);
+
+		/* A king and bishop can not mate a lone king */
+		play(& mut game, & ["Kxd2"]);
+		assert_eq!(game.joever, cnp::Joever::Draw);
+		assert_eq!(game.reason, Some("Draw by insufficient material"));
+	}
+}
@@ -0,0 +1,36 @@
+extern crate serde;
+
+use self::serde::{Deserialize, Serialize};
+
+/* The client handshake with what this program adds to it. A server that
+ * does not know the additions ignores them, and a client that leaves them
+ * out is taken to be a player. */
+#[derive(Serialize, Deserialize)]
+pub struct ClientHandshake
+{
+	pub server_color	: cnp::Color,
+	/* The client only wants to watch the game */
+	#[serde(default)]
+	pub spectate		: bool,
+	/* Picked by the player's client when it starts, the server only lets
+	 * a client with the same token resume the game */
+	#[serde(default)]
+	pub token		: Option<String>,
+	/* The client wants the clock times ahead of every state */
+	#[serde(default)]
+	pub times		: bool,
+}
+
+/* A token that nobody else is likely to pick */
+pub fn new_token() -> String
+{
+	use std::hash::{BuildHasher, Hasher};
+
+	/* The standard library seeds its hash keys randomly */
+	let r = ||
+		std::collections::hash_map::RandomState::new()
+			.build_hasher()
+			.finish();
+
+	format!("{:016x}{:016x}", r(), r())
+}
@@ -0,0 +1,23 @@
+extern crate chess_network_protocol as cnp;
+
+pub mod bridge;
+pub mod cli;
+pub mod clock;
+pub mod client;
+pub mod driver;
+pub mod engine;
+pub mod fen;
+pub mod game;
+pub mod handshake;
+pub mod local;
+pub mod net;
+pub mod pgn;
+pub mod referee;
+pub mod replay;
+pub mod rules;
+pub mod san;
+pub mod search;
+pub mod server;
+pub mod takeback;
+pub mod uci;
+pub mod util;
@@ -0,0 +1,292 @@
+use crate::clock::Clock;
+use crate::driver::{GameDriver, Status};
+use crate::game::GameState;
+use std::cell::{RefCell, RefMut};
+use crate::util::*;
+
+struct State
+{
+	game		: GameState,
+
+	flip		: bool,
+	offer_draw	: bool,
+	draw_offer	: Option<cnp::Color>,
+	message		: String,
+}
+
+/* Two players taking turns at the same window, the moves are played as soon
+ * as they are made so there is no thread */
+pub struct LocalDriver
+{
+	state		: RefCell<State>,
+}
+
+impl LocalDriver
+{
+	/* Play a game, which has been set up with its clock but not
+	 * started */
+	pub fn new(flip: bool, mut game: GameState) -> Self
+	{
+		game.start_clock();
+
+		Self
+		{
+			state		: RefCell::new(State
+			{
+				game,
+
+				flip,
+				offer_draw	: false,
+				draw_offer	: None,
+				message		: String::new(),
+			}),
+		}
+	}
+
+	/* Borrow the state, ending the game first if the player to move has
+	 * run out of time */
+	fn state(self: & Self) -> RefMut<'_, State>
+	{
+		let mut state = self.state.borrow_mut();
+
+		if state.game.check_flag()
+		{
+			state.message = String::from
+				(state.game.reason.unwrap_or("Out of time"));
+		}
+
+		state
+	}
+}
+
+impl GameDriver for LocalDriver
+{
+	fn features(self: & Self) -> Vec<cnp::Features>
+	{
+		self.state().game.features()
+	}
+
+	fn board(self: & Self) -> [[cnp::Piece; 8]; 8]
+	{
+		self.state().game.board
+	}
+
+	fn joever(self: & Self) -> cnp::Joever
+	{
+		self.state().game.joever
+	}
+
+	/* Whoever is to move is the one at the board */
+	fn color(self: & Self) -> cnp::Color
+	{
+		copy_color(& self.state().game.turn)
+	}
+
+	fn turn(self: & Self) -> cnp::Color
+	{
+		copy_color(& self.state().game.turn)
+	}
+
+	fn perspective(self: & Self) -> cnp::Color
+	{
+		let state = self.state();
+
+		if state.flip
+		{
+			copy_color(& state.game.turn)
+		}
+		else
+		{
+			cnp::Color::White
+		}
+	}
+
+	fn moves(self: & Self) -> Vec<cnp::Move>
+	{
+		self.state().game.moves.clone()
+	}
+
+	fn get_next_move(self: & Self) -> Option<cnp::Move>
+	{
+		None
+	}
+
+	fn set_next_move(self: & Self, m: Option<cnp::Move>)
+	{
+		let mut state = self.state();
+		let state = & mut * state;
+
+		let m = match m
+		{
+			Some(m) if state.game.joever == cnp::Joever::Ongoing => m,
+			_ => return,
+		};
+
+		let m = match state.game.find_move(& m)
+		{
+			Some(m) => m,
+			None => return,
+		};
+
+		let mover = copy_color(& state.game.turn);
+		state.game.play(m);
+		state.message = String::new();
+
+		/* Moving declines the opponent's offer */
+		if state.draw_offer == Some(inv_color(& mover))
+		{
+			state.draw_offer = None;
+			state.message = String::from("Draw offer declined");
+		}
+
+		if state.offer_draw && state.game.joever == cnp::Joever::Ongoing
+		{
+			state.draw_offer = Some(copy_color(& mover));
+			state.message = String::from("Draw offered");
+		}
+
+		state.offer_draw = false;
+
+		if let Some(reason) = state.game.reason
+		{
+			state.message = String::from(reason);
+		}
+	}
+
+	fn resign(self: & Self)
+	{
+		let mut state = self.state();
+
+		if state.game.joever == cnp::Joever::Ongoing
+		{
+			state.game.joever = color_win(& inv_color(& state.game.turn));
+			state.game.stop_clock();
+			state.message = match state.game.turn
+			{
+				cnp::Color::White => String::from("White resigned"),
+				cnp::Color::Black => String::from("Black resigned"),
+			};
+		}
+	}
+
+	fn offer_draw(self: & Self)
+	{
+		let mut state = self.state();
+
+		if state.game.joever != cnp::Joever::Ongoing
+		{
+			return;
+		}
+
+		/* Offering in return accepts the opponent's offer */
+		if state.draw_offer == Some(inv_color(& state.game.turn))
+		{
+			state.game.joever = cnp::Joever::Draw;
+			state.game.stop_clock();
+			state.draw_offer = None;
+			state.message = String::from("Draw agreed");
+		}
+		else if state.draw_offer.is_none()
+		{
+			state.offer_draw = true;
+			state.message = String::from
+				("A draw will be offered with your next move");
+		}
+	}
+
+	fn decline_draw(self: & Self)
+	{
+		let mut state = self.state();
+
+		if state.draw_offer == Some(inv_color(& state.game.turn))
+		{
+			state.draw_offer = None;
+			state.message = String::from("Draw declined");
+		}
+	}
+
+	fn draw_offer(self: & Self) -> Option<cnp::Color>
+	{
+		self.state().draw_offer.as_ref().map(copy_color)
+	}
+
+	fn clock(self: & Self) -> Option<Clock>
+	{
+		self.state().game.clock
+	}
+
+	fn history(self: & Self) -> Vec<String>
+	{
+		self.state().game.san.clone()
+	}
+
+	fn played(self: & Self) -> Vec<cnp::Move>
+	{
+		self.state().game.played.clone()
+	}
+
+	fn fen(self: & Self) -> String
+	{
+		self.state().game.fen().to_string()
+	}
+
+	/* Take back one move at a time, the draw offers go with it */
+	fn undo(self: & Self)
+	{
+		let mut state = self.state();
+
+		if state.game.undo()
+		{
+			state.offer_draw = false;
+			state.draw_offer = None;
+			state.message = String::from("Move taken back");
+		}
+	}
+
+	fn redo(self: & Self)
+	{
+		let mut state = self.state();
+
+		if state.game.joever == cnp::Joever::Ongoing && state.game.redo()
+		{
+			state.offer_draw = false;
+			state.draw_offer = None;
+			state.message = String::from
+				(state.game.reason.unwrap_or(""));
+		}
+	}
+
+	fn spectating(self: & Self) -> bool
+	{
+		false
+	}
+
+	/* There is no connection to wait for */
+	fn status(self: & Self) -> Status
+	{
+		Status::Connected
+	}
+
+	fn message(self: & Self) -> String
+	{
+		let state = self.state();
+
+		if !state.message.is_empty()
+			|| state.game.joever != cnp::Joever::Ongoing
+		{
+			state.message.clone()
+		}
+		else
+		{
+			match state.game.turn
+			{
+				cnp::Color::White => String::from("White to move"),
+				cnp::Color::Black => String::from("Black to move"),
+			}
+		}
+	}
+
+	fn quit(self: & mut Self)
+	{
+	}
+}
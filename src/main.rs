@@ -1,33 +1,158 @@
 extern crate chess_network_protocol as cnp;
 extern crate ggez;
-
-mod client;
-mod driver;
-mod server;
-mod util;
-
-use crate::client::ClientDriver;
-use crate::driver::GameDriver;
-use crate::server::ServerDriver;
-use crate::util::{PieceKind, piece_is_kind, piece_from_kind};
+extern crate tombergm_chess_gui;
+
+use tombergm_chess_gui::bridge;
+use tombergm_chess_gui::cli;
+use tombergm_chess_gui::clock::{Clock, TimeControl, format_time};
+use tombergm_chess_gui::client::ClientDriver;
+use tombergm_chess_gui::driver::{GameDriver, Seek, Status};
+use tombergm_chess_gui::engine::{BuiltinEngine, Engine, EngineDriver};
+use tombergm_chess_gui::fen::Fen;
+use tombergm_chess_gui::game::GameState;
+use tombergm_chess_gui::local::LocalDriver;
+use tombergm_chess_gui::pgn;
+use tombergm_chess_gui::replay::ReplayDriver;
+use tombergm_chess_gui::search::Limits;
+use tombergm_chess_gui::server::ServerDriver;
+use tombergm_chess_gui::uci::UciEngine;
+use tombergm_chess_gui::util::
+{
+	PieceKind,
+	inv_color,
+	piece_is_kind,
+	piece_from_kind,
+};
 
 fn main()
 {
-	let driver: Box<dyn GameDriver>;
+	let args: Vec<String> = std::env::args().skip(1).collect();
 
-	if std::env::args().len() == 1
-	{
-		driver = Box::new(ServerDriver::new());
-	}
-	else if std::env::args().len() == 2
+	let command = match cli::parse(& args)
 	{
-		let addr = std::env::args().nth(1).unwrap();
-		driver = Box::new(ClientDriver::new(addr));
-	}
-	else
+		Ok(command) => command,
+		Err(e) =>
+		{
+			eprintln!("error: {}", e);
+			eprintln!("Try '--help' for more information.");
+			std::process::exit(2);
+		},
+	};
+
+	let mut tags = pgn::Tags::new("Casual game");
+
+	let driver: Box<dyn GameDriver> = match command
 	{
-		return;
-	}
+		cli::Command::Host { bind, timeout, clock, uci, limits, fen } =>
+		{
+			tags.site = bind.clone();
+			tags.fen = setup_tag(& fen);
+
+			let game = new_game(fen, clock);
+			let driver = Box::new(ServerDriver::new(bind, timeout, game));
+
+			if let Some(path) = uci
+			{
+				bridge(driver, & path, limits);
+			}
+
+			driver
+		},
+		cli::Command::Join
+		{
+			addr,
+			color,
+			timeout,
+			spectate,
+			uci,
+			limits,
+		} =>
+		{
+			tags.site = addr.clone();
+
+			let driver = Box::new
+				(ClientDriver::new(addr, color, timeout, spectate));
+
+			if let Some(path) = uci
+			{
+				bridge(driver, & path, limits);
+			}
+
+			driver
+		},
+		cli::Command::Local { flip, clock, fen } =>
+		{
+			tags.fen = setup_tag(& fen);
+
+			Box::new(LocalDriver::new(flip, new_game(fen, clock)))
+		},
+		cli::Command::Ai { color, limits, uci } =>
+		{
+			let engine: Box<dyn Engine> = match uci
+			{
+				Some(path) => Box::new(spawn_engine(& path, limits)),
+				None => Box::new(BuiltinEngine { limits }),
+			};
+
+			let driver = Box::new(EngineDriver::new(color, engine));
+
+			/* The engine plays the side that the player does not */
+			if driver.color() == cnp::Color::White
+			{
+				tags.white = String::from("Player");
+				tags.black = String::from("Computer");
+			}
+			else
+			{
+				tags.white = String::from("Computer");
+				tags.black = String::from("Player");
+			}
+
+			driver
+		},
+		cli::Command::View { path, game, list } =>
+		{
+			let games = match std::fs::read_to_string(& path)
+				.map_err(|e| e.to_string())
+				.and_then(|text| pgn::parse(& text))
+			{
+				Ok(games) => games,
+				Err(e) =>
+				{
+					eprintln!("error: {}: {}", path, e);
+					std::process::exit(1);
+				},
+			};
+
+			if list
+			{
+				for (i, game) in games.iter().enumerate()
+				{
+					println!("{:4}  {}", i + 1, game.describe());
+				}
+
+				return;
+			}
+
+			if game > games.len()
+			{
+				eprintln!
+				(
+					"error: {} has only {} games",
+					path,
+					games.len(),
+				);
+				std::process::exit(2);
+			}
+
+			Box::new(ReplayDriver::new(games, game - 1))
+		},
+		cli::Command::Help =>
+		{
+			print!("{}", cli::USAGE);
+			return;
+		},
+	};
 
 	let (mut ctx, event_loop) =
 		ggez::ContextBuilder::new("chess-gui", "Tommy Bergman")
@@ -43,16 +168,107 @@ fn main()
 		.build()
 		.expect("");
 
-	let game = Game::new(& mut ctx, driver);
+	let game = Game::new(& mut ctx, driver, tags);
 
 	ggez::event::run(ctx, event_loop, game);
 }
 
+/* Set up a game from the command line options */
+fn new_game(fen: Option<Fen>, control: Option<TimeControl>) -> GameState
+{
+	let mut game = match fen
+	{
+		Some(fen) => GameState::from_fen(& fen),
+		None => GameState::new(),
+	};
+	game.clock = control.map(Clock::new);
+
+	game
+}
+
+/* The starting position to save with the game, if it is not the standard
+ * one */
+fn setup_tag(fen: & Option<Fen>) -> Option<String>
+{
+	fen.as_ref()
+		.filter(|fen| !fen.is_standard())
+		.map(|fen| fen.to_string())
+}
+
+fn spawn_engine(path: & str, limits: Limits) -> UciEngine
+{
+	match UciEngine::spawn(path, limits)
+	{
+		Ok(engine) => engine,
+		Err(e) =>
+		{
+			eprintln!("error: could not start {}: {}", path, e);
+			std::process::exit(1);
+		},
+	}
+}
+
+/* Play a network game with an engine instead of a window, and exit when it
+ * is over */
+fn bridge(driver: Box<dyn GameDriver>, path: & str, limits: Limits) -> !
+{
+	let engine = spawn_engine(path, limits);
+
+	match bridge::bridge_main(driver, engine)
+	{
+		Ok(joever) =>
+		{
+			println!("Game over: {:?}", joever);
+			std::process::exit(0);
+		},
+		Err(e) =>
+		{
+			eprintln!("error: {}", e);
+			std::process::exit(1);
+		},
+	}
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Button
+{
+	Resign,
+	OfferDraw,
+	AcceptDraw,
+	DeclineDraw,
+	AllowTakeback,
+	RefuseTakeback,
+}
+
+impl Button
+{
+	fn label(self: & Self) -> & 'static str
+	{
+		match self
+		{
+			Button::Resign		=> "Resign",
+			Button::OfferDraw	=> "Offer draw",
+			Button::AcceptDraw	=> "Accept",
+			Button::DeclineDraw	=> "Decline",
+			Button::AllowTakeback	=> "Allow",
+			Button::RefuseTakeback	=> "Refuse",
+		}
+	}
+}
+
 struct Game
 {
 	driver	: Box<dyn GameDriver>,
 	select	: Option<(i32, i32)>,
 	promo	: PieceKind,
+	/* How many lines the move list is scrolled up from its end */
+	scroll	: usize,
+	tags	: pgn::Tags,
+	/* Where the game and its positions are saved, and if the game has
+	 * been saved since it ended */
+	pgn	: String,
+	fen	: String,
+	saved	: bool,
 	images	: std::collections::HashMap<usize, ggez::graphics::Image>,
 }
 
@@ -64,18 +280,40 @@ impl Game
 	const SQUARE_H	: f32 = Self::SQUARE_W;
 	const PROMO_X	: f32 = Self::BOARD_X + Self::SQUARE_W * 8. + 50.;
 	const PROMO_Y	: f32 = Self::BOARD_Y;
-
-	pub fn new(ctx: & mut ggez::Context, driver: Box<dyn GameDriver>)
+	const BUTTON_X	: f32 = Self::PROMO_X;
+	const BUTTON_Y	: f32 = Self::PROMO_Y + Self::SQUARE_H * 5.;
+	const BUTTON_W	: f32 = Self::SQUARE_W;
+	const BUTTON_H	: f32 = Self::SQUARE_H / 2.;
+	const CLOCK_X	: f32 = Self::PROMO_X + Self::SQUARE_W + 50.;
+	const HISTORY_X	: f32 = Self::CLOCK_X;
+	const HISTORY_Y	: f32 = Self::BOARD_Y + Self::SQUARE_H;
+	const HISTORY_W	: f32 = 250.;
+	const HISTORY_H	: f32 = Self::SQUARE_H * 6.;
+	const LINE_H	: f32 = 24.;
+	const WINDOW_W	: f32 = Self::HISTORY_X + Self::HISTORY_W;
+	const WINDOW_H	: f32 = Self::BOARD_Y * 2. + Self::SQUARE_H * 8.;
+
+	pub fn new(ctx: & mut ggez::Context,
+			driver: Box<dyn GameDriver>,
+			tags: pgn::Tags)
 		-> Game
 	{
+		let stem = pgn::file_stem();
+
 		ctx.gfx.window().set_inner_size(
-			ggez::winit::dpi::PhysicalSize::new(900., 800.));
+			ggez::winit::dpi::PhysicalSize::new
+				(Self::WINDOW_W, Self::WINDOW_H));
 
 		let mut game = Game
 		{
 			driver,
 			select	: None,
 			promo	: PieceKind::Queen,
+			scroll	: 0,
+			tags,
+			pgn	: format!("{}.pgn", stem),
+			fen	: format!("{}.fen", stem),
+			saved	: false,
 			images	: std::collections::HashMap::new(),
 		};
 
@@ -115,12 +353,19 @@ impl Game
 			(x / Self::SQUARE_W) as i32,
 			(y / Self::SQUARE_H) as i32,
 		);
-		let mut promo_rank = 7;
+		/* The first row of the board is the eighth rank, where white
+		 * pawns are promoted, whichever way the board is turned */
+		let mut promo_rank = 0;
 
-		if self.driver.color() == cnp::Color::Black
+		if self.driver.perspective() == cnp::Color::Black
 		{
+			coords.0 = 7 - coords.0;
 			coords.1 = 7 - coords.1;
-			promo_rank = 0;
+		}
+
+		if self.driver.color() == cnp::Color::Black
+		{
+			promo_rank = 7;
 		}
 
 		if Some(coords) == self.select
@@ -206,6 +451,138 @@ impl Game
 
 		Ok(())
 	}
+
+	/* Write the game so far as PGN, to the same file every time so that
+	 * the last save has all of it */
+	fn save(self: & Self)
+	{
+		let result = pgn::save
+		(
+			& self.pgn,
+			& self.tags,
+			& self.driver.history(),
+			& self.driver.joever(),
+		);
+
+		match result
+		{
+			Ok(()) => println!("Game saved to {}", self.pgn),
+			Err(e) => eprintln!("Could not save {}: {}", self.pgn, e),
+		}
+	}
+
+	/* Print the position as FEN and add it to the positions that have
+	 * been saved from the game */
+	fn save_fen(self: & Self)
+	{
+		use std::io::Write;
+
+		let fen = self.driver.fen();
+		println!("{}", fen);
+
+		let result = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(& self.fen)
+			.and_then(|mut f| writeln!(f, "{}", fen));
+
+		match result
+		{
+			Ok(()) => println!("Position saved to {}", self.fen),
+			Err(e) => eprintln!("Could not save {}: {}", self.fen, e),
+		}
+	}
+
+	/* The move list, one line for each move of white and the answer of
+	 * black */
+	fn history_lines(self: & Self) -> Vec<[String; 3]>
+	{
+		self.driver.history()
+			.chunks(2)
+			.enumerate()
+			.map
+			(
+				|(i, pair)|
+				[
+					format!("{}.", i + 1),
+					pair[0].clone(),
+					pair.get(1).cloned().unwrap_or_default(),
+				]
+			)
+			.collect()
+	}
+
+	/* The buttons that are currently available, from top to bottom */
+	fn buttons(self: & Self) -> Vec<Button>
+	{
+		let mut buttons = Vec::new();
+
+		if self.driver.joever() == cnp::Joever::Ongoing
+			&& self.driver.status() == Status::Connected
+			&& !self.driver.spectating()
+		{
+			buttons.push(Button::Resign);
+
+			let c = self.driver.color();
+
+			match self.driver.draw_offer()
+			{
+				None =>
+				{
+					buttons.push(Button::OfferDraw);
+				},
+				Some(offer) if offer != c =>
+				{
+					buttons.push(Button::AcceptDraw);
+					buttons.push(Button::DeclineDraw);
+				},
+				_ => (),
+			}
+
+			if self.driver.takeback_request() == Some(inv_color(& c))
+			{
+				buttons.push(Button::AllowTakeback);
+				buttons.push(Button::RefuseTakeback);
+			}
+		}
+
+		buttons
+	}
+
+	fn click_button(self: & mut Self, _x: f32, y: f32)
+		-> ggez::GameResult
+	{
+		let y = (y / Self::BUTTON_H) as usize;
+
+		match self.buttons().get(y)
+		{
+			Some(Button::Resign) =>
+			{
+				self.select = None;
+				self.driver.set_next_move(None);
+				self.driver.resign();
+			},
+			Some(Button::OfferDraw) | Some(Button::AcceptDraw) =>
+			{
+				self.driver.offer_draw();
+			},
+			Some(Button::DeclineDraw) =>
+			{
+				self.driver.decline_draw();
+			},
+			Some(Button::AllowTakeback) =>
+			{
+				self.driver.answer_takeback(true);
+			},
+			Some(Button::RefuseTakeback) =>
+			{
+				self.driver.answer_takeback(false);
+			},
+			None => (),
+		}
+
+		Ok(())
+	}
 }
 
 
@@ -218,6 +595,7 @@ impl ggez::event::EventHandler for Game
 		-> ggez::GameResult
 	{
 		if self.driver.joever() != cnp::Joever::Ongoing
+			|| self.driver.spectating()
 		{
 			Ok(())
 		}
@@ -245,6 +623,16 @@ impl ggez::event::EventHandler for Game
 
 			self.click_promo(x, y)
 		}
+		else if x >= Self::BUTTON_X
+			&& y >= Self::BUTTON_Y
+			&& x < Self::BUTTON_X + Self::BUTTON_W
+			&& y < Self::BUTTON_Y + Self::BUTTON_H * 5.
+		{
+			let x = x - Self::BUTTON_X;
+			let y = y - Self::BUTTON_Y;
+
+			self.click_button(x, y)
+		}
 		else
 		{
 			Ok(())
@@ -257,13 +645,86 @@ impl ggez::event::EventHandler for Game
 				_repeated: bool)
 		-> Result<(), ggez::GameError>
 	{
+		use ggez::input::keyboard::KeyCode;
+
 		if let Some(kc) = input.keycode
 		{
-			if kc == ggez::input::keyboard::KeyCode::Escape
+			if kc == KeyCode::Escape
 			{
 				self.driver.quit();
 				ctx.request_quit();
 			}
+			else if kc == KeyCode::S
+			{
+				self.save();
+			}
+			else if kc == KeyCode::F
+			{
+				self.save_fen();
+			}
+			else if kc == KeyCode::U || kc == KeyCode::R
+			{
+				/* The selected piece may have moved */
+				self.select = None;
+				self.scroll = 0;
+
+				if kc == KeyCode::U
+				{
+					self.driver.undo();
+				}
+				else
+				{
+					self.driver.redo();
+				}
+			}
+
+			let seek = match kc
+			{
+				KeyCode::Left => Some(Seek::Previous),
+				KeyCode::Right => Some(Seek::Next),
+				KeyCode::Up | KeyCode::Home => Some(Seek::First),
+				KeyCode::Down | KeyCode::End => Some(Seek::Last),
+				KeyCode::PageUp => Some(Seek::PreviousGame),
+				KeyCode::PageDown => Some(Seek::NextGame),
+				_ => None,
+			};
+
+			/* Follow the moves with the move list */
+			if let Some(seek) = seek
+			{
+				self.driver.seek(seek);
+				self.scroll = 0;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn mouse_wheel_event(self: & mut Self,
+				ctx: & mut ggez::Context,
+				_x: f32, y: f32)
+		-> ggez::GameResult
+	{
+		let pos = ctx.mouse.position();
+
+		if pos.x < Self::HISTORY_X
+			|| pos.y < Self::HISTORY_Y
+			|| pos.y >= Self::HISTORY_Y + Self::HISTORY_H
+		{
+			return Ok(());
+		}
+
+		/* Scrolling up goes back towards the start of the game */
+		let visible = (Self::HISTORY_H / Self::LINE_H) as usize;
+		let hidden = self.history_lines().len().saturating_sub(visible);
+
+		if y > 0.
+		{
+			self.scroll = (self.scroll + 1).min(hidden);
+		}
+		else if y < 0.
+		{
+			self.scroll = self.scroll.saturating_sub(1);
 		}
 
 		Ok(())
@@ -275,6 +736,18 @@ impl ggez::event::EventHandler for Game
 		if self.driver.joever() != cnp::Joever::Ongoing
 		{
 			self.select = None;
+
+			if !self.saved
+			{
+				self.save();
+				self.saved = true;
+			}
+		}
+		else
+		{
+			/* The end was taken back, the game is saved again when
+			 * it ends anew */
+			self.saved = false;
 		}
 
 		Ok(())
@@ -320,7 +793,7 @@ impl ggez::event::EventHandler for Game
 				let p = board[y as usize][x as usize];
 
 				let dest_ul;
-				if self.driver.color() == cnp::Color::White
+				if self.driver.perspective() == cnp::Color::White
 				{
 					dest_ul =
 					[
@@ -338,7 +811,7 @@ impl ggez::event::EventHandler for Game
 					[
 						Self::BOARD_X
 							+ Self::SQUARE_W
-							* x as f32,
+							* (7 - x) as f32,
 						Self::BOARD_Y
 							+ Self::SQUARE_H
 							* (7 - y) as f32,
@@ -500,6 +973,109 @@ impl ggez::event::EventHandler for Game
 			}
 		}
 
+		for (y, b) in self.buttons().iter().enumerate()
+		{
+			let dest =
+			[
+				Self::BUTTON_X,
+				Self::BUTTON_Y + Self::BUTTON_H * y as f32,
+			];
+			let dest_c =
+			[
+				dest[0] + Self::BUTTON_W / 2.,
+				dest[1] + Self::BUTTON_H / 2. - 5.,
+			];
+
+			canvas.draw
+			(
+				& Quad,
+				DrawParam::default()
+					.scale
+					([
+						Self::BUTTON_W,
+						Self::BUTTON_H - 10.,
+					])
+					.color(Color::from_rgb(210, 190, 150))
+					.dest(dest),
+			);
+
+			let mut label = Text::new(b.label());
+			label.set_scale(20.);
+			label.set_layout(TextLayout::center());
+			canvas.draw
+			(
+				& label,
+				DrawParam::default()
+					.color(Color::BLACK)
+					.dest(dest_c),
+			);
+		}
+
+		/* The opponent's clock is drawn at their side of the board */
+		if let Some(clock) = self.driver.clock()
+		{
+			let color = self.driver.perspective();
+			let running = clock.running();
+
+			for (c, y) in
+			[
+				(inv_color(& color), Self::BOARD_Y),
+				(color, Self::BOARD_Y + Self::SQUARE_H * 7.),
+			]
+			{
+				let fg = if running.as_ref() == Some(& c)
+				{
+					Color::BLACK
+				}
+				else
+				{
+					Color::from_rgb(120, 100, 90)
+				};
+
+				let mut time = Text::new(format_time(clock.left(& c)));
+				time.set_scale(40.);
+				canvas.draw
+				(
+					& time,
+					DrawParam::default()
+						.color(fg)
+						.dest([Self::CLOCK_X, y]),
+				);
+			}
+		}
+
+		/* The last moves are shown unless the list has been scrolled
+		 * back */
+		{
+			let lines = self.history_lines();
+			let visible = (Self::HISTORY_H / Self::LINE_H) as usize;
+			let end = lines.len().saturating_sub(self.scroll);
+			let start = end.saturating_sub(visible);
+
+			for (i, line) in lines[start..end].iter().enumerate()
+			{
+				let y = Self::HISTORY_Y + Self::LINE_H * i as f32;
+
+				for (x, s) in
+				[
+					(Self::HISTORY_X, & line[0]),
+					(Self::HISTORY_X + 50., & line[1]),
+					(Self::HISTORY_X + 150., & line[2]),
+				]
+				{
+					let mut san = Text::new(s.as_str());
+					san.set_scale(20.);
+					canvas.draw
+					(
+						& san,
+						DrawParam::default()
+							.color(Color::BLACK)
+							.dest([x, y]),
+					);
+				}
+			}
+		}
+
 		let joever = match self.driver.joever()
 		{
 			cnp::Joever::Draw
@@ -532,6 +1108,38 @@ impl ggez::event::EventHandler for Game
 			);
 		}
 
+		let status = self.driver.status();
+
+		if status != Status::Connected
+		{
+			let color;
+
+			if status.is_error()
+			{
+				color = Color::RED;
+			}
+			else
+			{
+				color = Color::BLACK;
+			}
+
+			text.clear();
+			text.add(status.describe());
+			text.set_scale(25.);
+			canvas.draw
+			(
+				& text,
+				text_dp
+					.dest
+					([
+						Self::BOARD_X
+							+ Self::SQUARE_W * 4.,
+						Self::BOARD_Y - 50.,
+					])
+					.color(color),
+			);
+		}
+
 		text.clear();
 		text.add(self.driver.message());
 		text.set_scale(25.);
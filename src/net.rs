@@ -0,0 +1,385 @@
+use std::cell::Cell;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/* How often blocking operations check if they should give up */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/* How long a single connection attempt may take */
+const CONNECT_SLICE: Duration = Duration::from_millis(250);
+/* How long to keep trying to reach a host that does not answer */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/* Accept a connection, returns None if quit returns true before anyone
+ * connects */
+pub fn accept<F>(listener: & TcpListener, quit: F)
+	-> io::Result<Option<(TcpStream, SocketAddr)>>
+	where F: Fn() -> bool
+{
+	listener.set_nonblocking(true)?;
+
+	loop
+	{
+		match listener.accept()
+		{
+			Ok((stream, addr)) =>
+			{
+				/* Some platforms let the stream inherit the
+				 * listener's mode */
+				stream.set_nonblocking(false)?;
+
+				return Ok(Some((stream, addr)));
+			},
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock =>
+			{
+				if quit()
+				{
+					return Ok(None);
+				}
+
+				std::thread::sleep(POLL_INTERVAL);
+			},
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+/* Connect to an address, returns None if quit returns true before the
+ * connection is made */
+pub fn connect<F>(addr: & str, quit: F)
+	-> io::Result<Option<TcpStream>>
+	where F: Fn() -> bool
+{
+	let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+	let start = Instant::now();
+
+	loop
+	{
+		let mut err = io::Error::new
+		(
+			io::ErrorKind::InvalidInput,
+			"no addresses to connect to",
+		);
+		let mut timed_out = false;
+
+		for a in & addrs
+		{
+			if quit()
+			{
+				return Ok(None);
+			}
+
+			match TcpStream::connect_timeout(a, CONNECT_SLICE)
+			{
+				Ok(stream) => return Ok(Some(stream)),
+				Err(e) =>
+				{
+					if e.kind() == io::ErrorKind::TimedOut
+					{
+						timed_out = true;
+					}
+
+					err = e;
+				},
+			}
+		}
+
+		/* Only keep trying if the host might just be slow */
+		if !timed_out || start.elapsed() >= CONNECT_TIMEOUT
+		{
+			return Err(err);
+		}
+	}
+}
+
+/* How often a blocked read wakes up to send heartbeats and check on the
+ * peer */
+const READ_TICK: Duration = Duration::from_millis(100);
+/* A peer that stays silent for this many timeouts is dropped */
+const DROP_FACTOR: u32 = 6;
+/* Heartbeats are whitespace between messages, which JSON readers skip */
+const HEARTBEAT: & [u8] = b" ";
+
+/* A connection to a peer that may stop responding without closing the
+ * connection */
+pub struct Peer
+{
+	pub stream	: TcpStream,
+	timeout		: Option<Duration>,
+	heard		: Cell<Instant>,
+	sent		: Cell<Instant>,
+	heartbeats	: Cell<bool>,
+}
+
+impl Peer
+{
+	/* A timeout of None means the peer is never reported as silent and
+	 * no heartbeats are sent */
+	pub fn new(stream: TcpStream, timeout: Option<Duration>)
+		-> io::Result<Self>
+	{
+		stream.set_read_timeout(Some(READ_TICK))?;
+
+		Ok(Self
+		{
+			stream,
+			timeout,
+			heard		: Cell::new(Instant::now()),
+			sent		: Cell::new(Instant::now()),
+			heartbeats	: Cell::new(false),
+		})
+	}
+
+	/* Receive and deserialize a data type from the peer, reply tells if
+	 * the peer is expected to answer promptly. The quiet callback is told
+	 * when the peer goes silent and when it is heard from again. */
+	pub fn read<T, F>(self: & Self, reply: bool, quiet: F)
+		-> Result<T, serde_json::Error>
+		where T: for<'de> serde::de::Deserialize<'de>,
+			F: Fn(bool)
+	{
+		self.read_while(reply, quiet, || Ok(()))
+	}
+
+	/* Like read, but also calls idle regularly while waiting */
+	pub fn read_while<T, F, G>(self: & Self, reply: bool, quiet: F, idle: G)
+		-> Result<T, serde_json::Error>
+		where T: for<'de> serde::de::Deserialize<'de>,
+			F: Fn(bool),
+			G: Fn() -> Result<(), serde_json::Error>
+	{
+		let reader = PeerReader
+		{
+			peer		: self,
+			reply,
+			quiet,
+			idle,
+			boundary	: true,
+		};
+		let mut de = serde_json::Deserializer::from_reader(reader);
+
+		T::deserialize(& mut de)
+	}
+
+	/* Serialize and send a data type to the peer */
+	pub fn write<T>(self: & Self, t: & T)
+		-> Result<(), serde_json::Error>
+		where T: serde::ser::Serialize
+	{
+		serde_json::to_writer(& self.stream, t)?;
+
+		/* Silence is counted from the last thing that was said */
+		self.heard.set(Instant::now());
+		self.sent.set(Instant::now());
+
+		Ok(())
+	}
+
+	/* Send a data type that has already been serialized */
+	pub fn write_text(self: & Self, text: & str)
+		-> Result<(), serde_json::Error>
+	{
+		(& self.stream).write_all(text.as_bytes())
+			.map_err(serde_json::Error::io)?;
+
+		self.heard.set(Instant::now());
+		self.sent.set(Instant::now());
+
+		Ok(())
+	}
+
+	/* Check that the peer has not closed the connection, without waiting
+	 * for it to say anything */
+	pub fn alive(self: & Self) -> bool
+	{
+		let mut buf = [0; 1];
+
+		if self.stream.set_nonblocking(true).is_err()
+		{
+			return false;
+		}
+
+		let alive = match self.stream.peek(& mut buf)
+		{
+			Ok(n) => n > 0,
+			Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+		};
+
+		self.stream.set_nonblocking(false).is_ok() && alive
+	}
+
+	/* Check if the peer has started on a message, without waiting for
+	 * one. The heartbeats ahead of it are taken in, and a closed
+	 * connection counts as a message so that reading it fails. */
+	pub fn pending(self: & Self) -> io::Result<bool>
+	{
+		let mut buf = [0; 64];
+
+		self.stream.set_nonblocking(true)?;
+
+		let result = loop
+		{
+			let n = match self.stream.peek(& mut buf)
+			{
+				Ok(0) => break Ok(true),
+				Ok(n) => n,
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock =>
+				{
+					break Ok(false);
+				},
+				Err(e) => break Err(e),
+			};
+
+			self.heard.set(Instant::now());
+
+			let spaces = buf[..n]
+				.iter()
+				.take_while(|b| b.is_ascii_whitespace())
+				.count();
+
+			if buf[..spaces].contains(& HEARTBEAT[0])
+			{
+				self.heartbeats.set(true);
+			}
+
+			if spaces < n
+			{
+				break Ok(true);
+			}
+
+			if let Err(e) = (& self.stream).read(& mut buf[..spaces])
+			{
+				break Err(e);
+			}
+		};
+
+		self.stream.set_nonblocking(false)?;
+
+		result
+	}
+
+	/* Send a heartbeat if nothing has been sent for a while */
+	pub fn heartbeat(self: & Self)
+		-> Result<(), serde_json::Error>
+	{
+		if let Some(timeout) = self.timeout
+		{
+			if self.sent.get().elapsed() >= timeout / 4
+			{
+				(& self.stream).write_all(HEARTBEAT)
+					.map_err(serde_json::Error::io)?;
+				self.sent.set(Instant::now());
+			}
+		}
+
+		Ok(())
+	}
+}
+
+struct PeerReader<'a, F, G>
+	where F: Fn(bool),
+		G: Fn() -> Result<(), serde_json::Error>
+{
+	peer		: & 'a Peer,
+	reply		: bool,
+	quiet		: F,
+	idle		: G,
+	boundary	: bool,
+}
+
+impl<'a, F, G> io::Read for PeerReader<'a, F, G>
+	where F: Fn(bool),
+		G: Fn() -> Result<(), serde_json::Error>
+{
+	fn read(self: & mut Self, buf: & mut [u8]) -> io::Result<usize>
+	{
+		loop
+		{
+			match (& self.peer.stream).read(buf)
+			{
+				Ok(n) =>
+				{
+					if n > 0
+					{
+						self.peer.heard.set(Instant::now());
+						(self.quiet)(false);
+					}
+
+					/* Whitespace ahead of a message means
+					 * that the peer sends heartbeats */
+					if self.boundary
+					{
+						for b in & buf[..n]
+						{
+							if *b == HEARTBEAT[0]
+							{
+								self.peer.heartbeats
+									.set(true);
+							}
+							else if !b
+							.is_ascii_whitespace()
+							{
+								self.boundary = false;
+								break;
+							}
+						}
+					}
+
+					return Ok(n);
+				},
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock
+					|| e.kind() == io::ErrorKind::TimedOut =>
+				{
+					self.peer.heartbeat()
+						.map_err(io::Error::from)?;
+					(self.idle)().map_err(io::Error::from)?;
+					self.check_silence()?;
+				},
+				Err(e) if e.kind() == io::ErrorKind::Interrupted =>
+				{
+				},
+				Err(e) => return Err(e),
+			}
+		}
+	}
+}
+
+impl<'a, F, G> PeerReader<'a, F, G>
+	where F: Fn(bool),
+		G: Fn() -> Result<(), serde_json::Error>
+{
+	fn check_silence(self: & Self) -> io::Result<()>
+	{
+		let timeout = match self.peer.timeout
+		{
+			Some(timeout) => timeout,
+			None => return Ok(()),
+		};
+
+		/* A peer that does not send heartbeats may be silent for as
+		 * long as it likes while it is thinking */
+		if !self.reply && !self.peer.heartbeats.get()
+		{
+			return Ok(());
+		}
+
+		let silent = self.peer.heard.get().elapsed();
+
+		if silent >= timeout * DROP_FACTOR
+		{
+			return Err(io::Error::new
+			(
+				io::ErrorKind::TimedOut,
+				"opponent stopped responding",
+			));
+		}
+
+		if silent >= timeout
+		{
+			(self.quiet)(true);
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,709 @@
+use crate::fen;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* How long the lines of the move text may be */
+const LINE_LIMIT: usize = 79;
+
+/* The tags of the Seven Tag Roster that do not follow from the game itself,
+ * unknown ones are written as ? */
+pub struct Tags
+{
+	pub event	: String,
+	pub site	: String,
+	pub white	: String,
+	pub black	: String,
+	/* The starting position of a game that did not start from the
+	 * standard one */
+	pub fen		: Option<String>,
+}
+
+impl Tags
+{
+	pub fn new(event: & str) -> Self
+	{
+		Self
+		{
+			event	: String::from(event),
+			site	: String::from("?"),
+			white	: String::from("?"),
+			black	: String::from("?"),
+			fen	: None,
+		}
+	}
+}
+
+/* The year, month and day of a number of days since 1970-01-01 */
+fn civil_date(days: u64) -> (u64, u64, u64)
+{
+	/* Count from 0000-03-01, so that leap days fall at the end of the
+	 * year, in eras of 400 years */
+	let days = days + 719468;
+	let era = days / 146097;
+	let doe = days % 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10
+	{
+		mp + 3
+	}
+	else
+	{
+		mp - 9
+	};
+	let year = era * 400 + yoe + (month <= 2) as u64;
+
+	(year, month, day)
+}
+
+/* The time now, in UTC, as a date in the format of the Date tag and a time
+ * of day */
+fn now() -> (String, String)
+{
+	let secs = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |d| d.as_secs());
+	let (year, month, day) = civil_date(secs / 86400);
+	let secs = secs % 86400;
+
+	(
+		format!("{:04}.{:02}.{:02}", year, month, day),
+		format!("{:02}{:02}{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+	)
+}
+
+/* A name to save the files of a game that starts now under, such as
+ * game-20240131-120000, without the extension */
+pub fn file_stem() -> String
+{
+	let (date, time) = now();
+
+	format!("game-{}-{}", date.replace('.', ""), time)
+}
+
+/* The result of a game as PGN writes it, * for a game that is not over */
+pub fn result(joever: & cnp::Joever) -> & 'static str
+{
+	match joever
+	{
+		cnp::Joever::White => "1-0",
+		cnp::Joever::Black => "0-1",
+		cnp::Joever::Draw => "1/2-1/2",
+		cnp::Joever::Indeterminate | cnp::Joever::Ongoing => "*",
+	}
+}
+
+fn escape(s: & str) -> String
+{
+	s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/* Write a game as PGN, with the moves in standard algebraic notation */
+pub fn to_pgn(tags: & Tags, history: & [String], joever: & cnp::Joever)
+	-> String
+{
+	let result = result(joever);
+	let mut s = String::new();
+	let mut tag_list = vec!
+	[
+		("Event", tags.event.clone()),
+		("Site", tags.site.clone()),
+		("Date", now().0),
+		("Round", String::from("-")),
+		("White", tags.white.clone()),
+		("Black", tags.black.clone()),
+		("Result", String::from(result)),
+	];
+
+	/* The moves that came before the starting position */
+	let mut start = 0;
+
+	if let Some(ref position) = tags.fen
+	{
+		tag_list.push(("SetUp", String::from("1")));
+		tag_list.push(("FEN", position.clone()));
+
+		if let Ok(position) = fen::parse(position)
+		{
+			start = (position.fullmoves as usize - 1) * 2
+				+ (position.turn == cnp::Color::Black) as usize;
+		}
+	}
+
+	for (name, value) in tag_list
+	{
+		s.push_str(& format!("[{} \"{}\"]\n", name, escape(& value)));
+	}
+
+	s.push('\n');
+
+	/* Move numbers go before the moves of white, and before the first
+	 * move if black makes it */
+	let mut words = Vec::new();
+
+	for (i, san) in history.iter().enumerate()
+	{
+		let ply = start + i;
+
+		if ply % 2 == 0
+		{
+			words.push(format!("{}. {}", ply / 2 + 1, san));
+		}
+		else if i == 0
+		{
+			words.push(format!("{}... {}", ply / 2 + 1, san));
+		}
+		else
+		{
+			words.push(san.clone());
+		}
+	}
+
+	words.push(String::from(result));
+
+	let mut line = String::new();
+
+	for word in words
+	{
+		if !line.is_empty() && line.len() + 1 + word.len() > LINE_LIMIT
+		{
+			s.push_str(& line);
+			s.push('\n');
+			line.clear();
+		}
+
+		if !line.is_empty()
+		{
+			line.push(' ');
+		}
+
+		line.push_str(& word);
+	}
+
+	s.push_str(& line);
+	s.push('\n');
+
+	s
+}
+
+/* Save a game as PGN, replacing what was saved there before */
+pub fn save(path: & str,
+		tags: & Tags,
+		history: & [String],
+		joever: & cnp::Joever)
+	-> io::Result<()>
+{
+	std::fs::write(path, to_pgn(tags, history, joever))
+}
+
+/* A game read from a PGN file, with its moves as they are written there */
+pub struct PgnGame
+{
+	pub tags	: Vec<(String, String)>,
+	pub moves	: Vec<String>,
+	pub result	: String,
+}
+
+impl PgnGame
+{
+	fn new() -> Self
+	{
+		Self
+		{
+			tags	: Vec::new(),
+			moves	: Vec::new(),
+			result	: String::new(),
+		}
+	}
+
+	/* The value of a tag, ? if the game does not have it */
+	pub fn tag(self: & Self, name: & str) -> & str
+	{
+		self.tags
+			.iter()
+			.find(|(n, _)| n == name)
+			.map_or("?", |(_, v)| v.as_str())
+	}
+
+	pub fn joever(self: & Self) -> cnp::Joever
+	{
+		match self.result.as_str()
+		{
+			"1-0" => cnp::Joever::White,
+			"0-1" => cnp::Joever::Black,
+			"1/2-1/2" => cnp::Joever::Draw,
+			_ => cnp::Joever::Ongoing,
+		}
+	}
+
+	/* A line that tells the game apart from the others in a file */
+	pub fn describe(self: & Self) -> String
+	{
+		format!
+		(
+			"{} - {}, {}, {}, {}",
+			self.tag("White"),
+			self.tag("Black"),
+			self.tag("Event"),
+			self.tag("Date"),
+			self.result,
+		)
+	}
+}
+
+/* Reads the text of a PGN file one character at a time, keeping track of
+ * the line for error messages */
+struct Scanner<'a>
+{
+	chars		: std::iter::Peekable<std::str::Chars<'a>>,
+	line		: usize,
+}
+
+impl<'a> Scanner<'a>
+{
+	fn next(self: & mut Self) -> Option<char>
+	{
+		let c = self.chars.next();
+
+		if c == Some('\n')
+		{
+			self.line += 1;
+		}
+
+		c
+	}
+
+	fn peek(self: & mut Self) -> Option<char>
+	{
+		self.chars.peek().copied()
+	}
+
+	/* Skip up to and including a character, failing if the text ends
+	 * first */
+	fn skip_past(self: & mut Self, end: char, what: & str)
+		-> Result<(), String>
+	{
+		let line = self.line;
+
+		loop
+		{
+			match self.next()
+			{
+				Some(c) if c == end => return Ok(()),
+				Some(_) => (),
+				None => return Err(format!
+					("unterminated {} on line {}", what, line)),
+			}
+		}
+	}
+
+	/* Read a tag pair such as [White "Someone"], after its [ */
+	fn tag(self: & mut Self) -> Result<(String, String), String>
+	{
+		let line = self.line;
+		let bad = || format!("malformed tag on line {}", line);
+		let mut name = String::new();
+		let mut value = String::new();
+
+		while let Some(c) = self.peek()
+		{
+			if c.is_whitespace() || c == '"' || c == ']'
+			{
+				break;
+			}
+
+			name.push(c);
+			self.next();
+		}
+
+		while self.peek().map_or(false, char::is_whitespace)
+		{
+			self.next();
+		}
+
+		if name.is_empty() || self.next() != Some('"')
+		{
+			return Err(bad());
+		}
+
+		loop
+		{
+			match self.next()
+			{
+				Some('"') => break,
+				Some('\\') => value.push(self.next().ok_or_else(bad)?),
+				Some(c) => value.push(c),
+				None => return Err(bad()),
+			}
+		}
+
+		self.skip_past(']', "tag")?;
+
+		Ok((name, value))
+	}
+
+	/* Skip a variation, which may hold variations and comments of its
+	 * own, after its ( */
+	fn variation(self: & mut Self) -> Result<(), String>
+	{
+		let line = self.line;
+
+		loop
+		{
+			match self.next()
+			{
+				Some(')') => return Ok(()),
+				Some('(') => self.variation()?,
+				Some('{') => self.skip_past('}', "comment")?,
+				Some(_) => (),
+				None => return Err(format!
+					("unterminated variation on line {}", line)),
+			}
+		}
+	}
+}
+
+/* Read the games of a PGN file. Comments, variations and annotations are
+ * left out. */
+pub fn parse(text: & str) -> Result<Vec<PgnGame>, String>
+{
+	let mut s = Scanner
+	{
+		chars		: text.chars().peekable(),
+		line		: 1,
+	};
+	let mut games = Vec::new();
+	let mut game = PgnGame::new();
+
+	while let Some(c) = s.next()
+	{
+		match c
+		{
+			c if c.is_whitespace() => (),
+			'[' =>
+			{
+				/* Tags after moves belong to the next game, when
+				 * the last one has no result */
+				if !game.moves.is_empty()
+				{
+					games.push(std::mem::replace
+						(& mut game, PgnGame::new()));
+				}
+
+				game.tags.push(s.tag()?);
+			},
+			'{' => s.skip_past('}', "comment")?,
+			';' | '%' =>
+			{
+				while s.peek().map_or(false, |c| c != '\n')
+				{
+					s.next();
+				}
+			},
+			'(' => s.variation()?,
+			')' => return Err(format!
+				("unexpected ) on line {}", s.line)),
+			_ =>
+			{
+				let mut token = String::from(c);
+
+				while let Some(c) = s.peek()
+				{
+					if c.is_whitespace() || "[]{}();".contains(c)
+					{
+						break;
+					}
+
+					token.push(c);
+					s.next();
+				}
+
+				match token.as_str()
+				{
+					"1-0" | "0-1" | "1/2-1/2" | "*" =>
+					{
+						game.result = token;
+						games.push(std::mem::replace
+							(& mut game, PgnGame::new()));
+					},
+					/* Numeric annotations */
+					t if t.starts_with('$') => (),
+					t =>
+					{
+						/* Move numbers may be written
+						 * right up against the move,
+						 * castling may be written with
+						 * zeros */
+						let number = t.trim_start_matches
+							(|c: char| c.is_ascii_digit());
+						let san = if number.starts_with('.')
+						{
+							number.trim_start_matches('.')
+						}
+						else
+						{
+							t
+						};
+						let san = san.trim_end_matches
+							(['!', '?']);
+
+						if !san.is_empty()
+						{
+							game.moves.push
+								(String::from(san));
+						}
+					},
+				}
+			},
+		}
+	}
+
+	if !game.tags.is_empty() || !game.moves.is_empty()
+	{
+		games.push(game);
+	}
+
+	/* A game that ends without a result token goes by its tag */
+	for game in & mut games
+	{
+		if game.result.is_empty()
+		{
+			game.result = match game.tag("Result")
+			{
+				"?" => String::from("*"),
+				r => String::from(r),
+			};
+		}
+	}
+
+	if games.is_empty()
+	{
+		return Err(String::from("no games found"));
+	}
+
+	Ok(games)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn tags() -> Tags
+	{
+		Tags
+		{
+			white	: String::from("Alice"),
+			black	: String::from("Bob \"the\" Bot"),
+			..Tags::new("Casual game")
+		}
+	}
+
+	fn history(moves: & [& str]) -> Vec<String>
+	{
+		moves.iter().map(|m| String::from(*m)).collect()
+	}
+
+	/* The move text, after the tags and the empty line */
+	fn move_text(pgn: & str) -> & str
+	{
+		pgn.split_once("\n\n").unwrap().1
+	}
+
+	#[test]
+	fn seven_tag_roster()
+	{
+		let pgn = to_pgn(& tags(), & [], & cnp::Joever::Ongoing);
+		let lines: Vec<& str> = pgn.lines().collect();
+
+		assert_eq!(lines[0], "[Event \"Casual game\"]");
+		assert_eq!(lines[1], "[Site \"?\"]");
+		assert!(lines[2].starts_with("[Date \"")
+			&& lines[2].len() == "[Date \"2024.01.31\"]".len());
+		assert_eq!(lines[3], "[Round \"-\"]");
+		assert_eq!(lines[4], "[White \"Alice\"]");
+		assert_eq!(lines[5], "[Black \"Bob \\\"the\\\" Bot\"]");
+		assert_eq!(lines[6], "[Result \"*\"]");
+		assert_eq!(lines[7], "");
+		assert_eq!(lines[8], "*");
+		assert_eq!(lines.len(), 9);
+	}
+
+	#[test]
+	fn results()
+	{
+		for (joever, r) in
+		[
+			(cnp::Joever::White, "1-0"),
+			(cnp::Joever::Black, "0-1"),
+			(cnp::Joever::Draw, "1/2-1/2"),
+			(cnp::Joever::Indeterminate, "*"),
+			(cnp::Joever::Ongoing, "*"),
+		]
+		{
+			let pgn = to_pgn(& tags(), & history(& ["e4"]), & joever);
+
+			assert!(pgn.contains(& format!("[Result \"{}\"]\n", r)));
+			assert_eq!(move_text(& pgn), format!("1. e4 {}\n", r));
+		}
+	}
+
+	#[test]
+	fn move_numbers()
+	{
+		let moves = history(& ["e4", "e5", "Nf3", "Nc6", "Bb5"]);
+		let pgn = to_pgn(& tags(), & moves, & cnp::Joever::Ongoing);
+
+		assert_eq!(move_text(& pgn), "1. e4 e5 2. Nf3 Nc6 3. Bb5 *\n");
+	}
+
+	/* A game set up with black to move numbers its first move N... */
+	#[test]
+	fn move_numbers_from_a_position()
+	{
+		let position = "4k3/8/8/8/8/8/4P3/4K3 b - - 0 12";
+		let tags = Tags
+		{
+			fen	: Some(String::from(position)),
+			..tags()
+		};
+		let moves = history(& ["Kd7", "e4", "Ke6"]);
+		let pgn = to_pgn(& tags, & moves, & cnp::Joever::Ongoing);
+
+		assert!(pgn.contains("[Result \"*\"]\n[SetUp \"1\"]\n"));
+		assert!(pgn.contains(& format!("[FEN \"{}\"]\n\n", position)));
+		assert_eq!(move_text(& pgn), "12... Kd7 13. e4 Ke6 *\n");
+	}
+
+	#[test]
+	fn line_wrap()
+	{
+		let moves: Vec<String> = (0..60)
+			.map(|i| String::from(["Nf3", "Nf6", "Ng1", "Ng8"][i % 4]))
+			.collect();
+		let pgn = to_pgn(& tags(), & moves, & cnp::Joever::Draw);
+		let text = move_text(& pgn);
+		let lines: Vec<& str> = text.lines().collect();
+
+		assert!(lines.len() > 1);
+
+		for line in & lines
+		{
+			assert!(line.len() <= LINE_LIMIT);
+			/* Only moves go at the start of a line */
+			assert!(!line.starts_with(' ') && !line.ends_with(' '));
+		}
+
+		/* Each line but the last is full, the next move and its number
+		 * would not fit */
+		for line in & lines[..lines.len() - 1]
+		{
+			assert!(line.len() + " 10. Nf3".len() > LINE_LIMIT);
+		}
+
+		/* A move number stays on the line of its move */
+		assert!(lines.iter().all(|line| !line.ends_with('.')));
+
+		assert_eq!(lines.join(" "), text.split_whitespace()
+			.collect::<Vec<_>>()
+			.join(" "));
+		assert!(text.starts_with("1. Nf3 Nf6 2. Ng1 Ng8 3. Nf3"));
+		assert!(text.ends_with("30. Ng1 Ng8 1/2-1/2\n"));
+	}
+
+	#[test]
+	fn save_writes_the_game()
+	{
+		let path = std::env::temp_dir()
+			.join(format!("pgn-save-{}.pgn", std::process::id()));
+		let path = path.to_str().unwrap();
+		let moves = history(& ["f3", "e5", "g4", "Qh4#"]);
+
+		std::fs::write(path, "something else").unwrap();
+		save(path, & tags(), & moves, & cnp::Joever::Black).unwrap();
+
+		let text = std::fs::read_to_string(path).unwrap();
+		std::fs::remove_file(path).unwrap();
+
+		assert_eq!(text, to_pgn(& tags(), & moves, & cnp::Joever::Black));
+		assert_eq!(move_text(& text), "1. f3 e5 2. g4 Qh4# 0-1\n");
+	}
+
+	#[test]
+	fn comments_variations_and_annotations()
+	{
+		let text = "[Event \"Test\"]\n\
+			[White \"A \\\"quoted\\\" name\"]\n\
+			\n\
+			1.e4 {a comment (with a bracket)} e5 $1 2. Nf3!? \
+			(2. Bc4 (2. f4 exf4) {another} Nf6) Nc6?? ; to the end\n\
+			% escaped line\n\
+			3. Bb5 a6 4. O-O 1-0\n";
+		let games = parse(text).unwrap();
+
+		assert_eq!(games.len(), 1);
+		assert_eq!(games[0].tag("Event"), "Test");
+		assert_eq!(games[0].tag("White"), "A \"quoted\" name");
+		assert_eq!(games[0].tag("Round"), "?");
+		assert_eq!(games[0].moves,
+			["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "O-O"]);
+		assert_eq!(games[0].result, "1-0");
+		assert_eq!(games[0].joever(), cnp::Joever::White);
+	}
+
+	#[test]
+	fn several_games()
+	{
+		let text = "[Event \"First\"]\n\n1. e4 e5 1/2-1/2\n\n\
+			[Event \"Second\"]\n[Result \"0-1\"]\n\n1. d4 d5\n\n\
+			[Event \"Third\"]\n\n*\n";
+		let games = parse(text).unwrap();
+
+		assert_eq!(games.len(), 3);
+		assert_eq!(games[0].tag("Event"), "First");
+		assert_eq!(games[0].result, "1/2-1/2");
+		/* The second game has no result token, its tag goes */
+		assert_eq!(games[1].tag("Event"), "Second");
+		assert_eq!(games[1].moves, ["d4", "d5"]);
+		assert_eq!(games[1].result, "0-1");
+		assert_eq!(games[2].tag("Event"), "Third");
+		assert!(games[2].moves.is_empty());
+		assert_eq!(games[2].joever(), cnp::Joever::Ongoing);
+	}
+
+	#[test]
+	fn errors()
+	{
+		assert_eq!(parse("").err().unwrap(), "no games found");
+		assert_eq!(parse("\n1. e4 {unfinished").err().unwrap(),
+			"unterminated comment on line 2");
+		assert_eq!(parse("1. e4 (1. d4\n").err().unwrap(),
+			"unterminated variation on line 1");
+		assert_eq!(parse("1. e4 )").err().unwrap(),
+			"unexpected ) on line 1");
+		assert_eq!(parse("\n\n[Event]").err().unwrap(),
+			"malformed tag on line 3");
+	}
+
+	/* What is written can be read back */
+	#[test]
+	fn round_trip()
+	{
+		let moves = history(& ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6",
+			"Bxc6", "dxc6", "O-O", "f6", "d4", "exd4", "Nxd4", "c5",
+			"Nb3", "Qxd1", "Rxd1", "Bg4", "f3", "Be6", "Nc3", "O-O-O",
+			"Be3", "b6", "a4", "Kb7", "a5", "c4", "Nd2", "b5"]);
+		let pgn = to_pgn(& tags(), & moves, & cnp::Joever::Draw);
+		let games = parse(& pgn).unwrap();
+
+		assert_eq!(games.len(), 1);
+		assert_eq!(games[0].moves, moves);
+		assert_eq!(games[0].result, "1/2-1/2");
+		assert_eq!(games[0].tag("White"), "Alice");
+		assert_eq!(games[0].tag("Black"), "Bob \"the\" Bot");
+		assert_eq!(games[0].describe().split(", ").nth(1),
+			Some("Casual game"));
+	}
+}
@@ -0,0 +1,481 @@
+extern crate serde;
+
+use crate::clock::{Clock, TimeControl};
+use crate::fen::Fen;
+use crate::game::GameState;
+use crate::handshake::ClientHandshake;
+use crate::net;
+use crate::util::*;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/* How often the players in the lobby are checked on */
+const LOBBY_INTERVAL: Duration = Duration::from_secs(1);
+
+/* A player's connection, along with what it asked for in its handshake */
+struct Player
+{
+	peer		: net::Peer,
+	/* The player wants the clock times ahead of every state */
+	times		: bool,
+}
+
+impl Player
+{
+	/* Send a message with a new state, along with the clock times if the
+	 * player wants them */
+	fn update(self: & Self, game: & GameState, msg: & cnp::ServerToClient)
+		-> Result<(), serde_json::Error>
+	{
+		if let (true, Some(clock)) = (self.times, game.clock)
+		{
+			self.peer.write(& clock.to_sync())?;
+		}
+
+		self.peer.write(msg)
+	}
+}
+
+/* Players that are waiting for an opponent, by the color they play */
+struct Lobby
+{
+	white		: VecDeque<Player>,
+	black		: VecDeque<Player>,
+}
+
+/* A connection error, along with the color of the player whose connection
+ * failed */
+type Fault = (cnp::Color, serde_json::Error);
+
+fn handshake(game: & GameState) -> cnp::ServerToClientHandshake
+{
+	cnp::ServerToClientHandshake
+	{
+		features	: game.features(),
+		board		: game.board,
+		moves		: game.moves.clone(),
+		joever		: game.joever,
+	}
+}
+
+fn state_msg(game: & GameState, move_made: cnp::Move) -> cnp::ServerToClient
+{
+	cnp::ServerToClient::State
+	{
+		board		: game.board,
+		moves		: game.moves.clone(),
+		joever		: game.joever,
+		move_made,
+	}
+}
+
+fn draw_msg(game: & GameState) -> cnp::ServerToClient
+{
+	cnp::ServerToClient::Draw
+	{
+		board		: game.board,
+		moves		: game.moves.clone(),
+	}
+}
+
+/* Play out a game between two players, acting as the server for both */
+fn referee(game: & mut GameState, white: & Player, black: & Player)
+	-> Result<(), Fault>
+{
+	let mut draw_offer: Option<cnp::Color> = None;
+
+	game.start_clock();
+
+	while game.joever == cnp::Joever::Ongoing
+	{
+		let (mover, waiter) = match game.turn
+		{
+			cnp::Color::White => (white, black),
+			cnp::Color::Black => (black, white),
+		};
+		let mover_color = copy_color(& game.turn);
+		let waiter_color = inv_color(& game.turn);
+
+		/* Keep the waiting player aware that we are still here while
+		 * the other one thinks */
+		let waiter_failed = Cell::new(false);
+		let msg = mover.peer.read_while::<cnp::ClientToServer, _, _>
+		(
+			false,
+			|_| (),
+			||
+			{
+				let result = waiter.peer.heartbeat();
+				waiter_failed.set(result.is_err());
+				result?;
+
+				/* Stop waiting if the mover runs out of time */
+				if game.clock.map_or(false, |c| c.flagged().is_some())
+				{
+					return Err(serde_json::Error::io(io::Error::new
+					(
+						io::ErrorKind::Other,
+						"out of time",
+					)));
+				}
+
+				Ok(())
+			},
+		);
+
+		if msg.is_err() && !waiter_failed.get() && game.check_flag()
+		{
+			let msg = cnp::ServerToClient::Error
+			{
+				board		: game.board,
+				moves		: game.moves.clone(),
+				joever		: game.joever,
+				message		: String::from
+					(game.reason.unwrap_or("Out of time")),
+			};
+			mover.update(game, & msg)
+				.map_err(|e| (copy_color(& mover_color), e))?;
+			waiter.update(game, & msg)
+				.map_err(|e| (copy_color(& waiter_color), e))?;
+
+			break;
+		}
+
+		let msg = msg.map_err
+		(
+			|e|
+			if waiter_failed.get()
+			{
+				(copy_color(& waiter_color), e)
+			}
+			else
+			{
+				(copy_color(& mover_color), e)
+			}
+		)?;
+
+		let fault_mover = |e| (copy_color(& mover_color), e);
+		let fault_waiter = |e| (copy_color(& waiter_color), e);
+
+		match msg
+		{
+			cnp::ClientToServer::Move(m) =>
+			{
+				let m = match game.find_move(& m)
+				{
+					Some(m) => m,
+					None =>
+					{
+						let msg = cnp::ServerToClient::Error
+						{
+							board		: game.board,
+							moves		: game.moves.clone(),
+							joever		: game.joever,
+							message		: String::from
+								("That move is invalid"),
+						};
+						mover.update(game, & msg)
+							.map_err(fault_mover)?;
+
+						continue;
+					},
+				};
+
+				game.play(m);
+
+				/* Moving declines the other player's offer */
+				if draw_offer == Some(copy_color(& waiter_color))
+				{
+					draw_offer = None;
+				}
+
+				/* The mover's offer is passed on after the
+				 * move */
+				let offer = draw_offer == Some(copy_color(& mover_color))
+					&& game.joever == cnp::Joever::Ongoing;
+
+				mover.update(game, & state_msg(game, m))
+					.map_err(fault_mover)?;
+				waiter.update(game, & state_msg(game, m))
+					.map_err(fault_waiter)?;
+
+				if offer
+				{
+					waiter.update(game, & draw_msg(game))
+						.map_err(fault_waiter)?;
+				}
+			},
+			cnp::ClientToServer::Resign =>
+			{
+				game.joever = color_win(& waiter_color);
+
+				let msg = cnp::ServerToClient::Resigned
+				{
+					board		: game.board,
+					joever		: game.joever,
+				};
+				mover.update(game, & msg).map_err(fault_mover)?;
+				waiter.update(game, & msg).map_err(fault_waiter)?;
+			},
+			cnp::ClientToServer::Draw =>
+			{
+				if draw_offer == Some(copy_color(& waiter_color))
+				{
+					/* The mover accepts and expects no answer,
+					 * the waiter takes a draw in response to its
+					 * offer as an acceptance */
+					game.joever = cnp::Joever::Draw;
+
+					waiter.update(game, & draw_msg(game))
+						.map_err(fault_waiter)?;
+				}
+				else
+				{
+					/* The mover sends its move next */
+					draw_offer = Some(copy_color(& mover_color));
+				}
+			},
+		}
+	}
+
+	Ok(())
+}
+
+/* Run a game and tell the remaining player if the other one drops out */
+fn run_game(mut game: GameState, white: Player, black: Player)
+{
+	println!("Game started");
+
+	if let Err((c, e)) = referee(& mut game, & white, & black)
+	{
+		println!("Game abandoned: {}", e);
+
+		/* The player that dropped out forfeits */
+		game.joever = color_win(& inv_color(& c));
+
+		let msg = cnp::ServerToClient::Resigned
+		{
+			board		: game.board,
+			joever		: game.joever,
+		};
+
+		let _ = match c
+		{
+			cnp::Color::White => black.update(& game, & msg),
+			cnp::Color::Black => white.update(& game, & msg),
+		};
+	}
+	else
+	{
+		match game.reason
+		{
+			Some(reason) => println!("Game over: {}", reason),
+			None => println!("Game over"),
+		}
+	}
+}
+
+/* Pair a new connection with a waiting opponent, or make it wait for
+ * one */
+fn join(stream: TcpStream,
+		timeout: Option<Duration>,
+		control: Option<TimeControl>,
+		fen: Option<Arc<Fen>>,
+		lobby_rc: Arc<Mutex<Lobby>>)
+	-> Result<(), serde_json::Error>
+{
+	let peer = net::Peer::new(stream, timeout)
+		.map_err(serde_json::Error::io)?;
+
+	let h = peer.read::<ClientHandshake, _>(true, |_| ())?;
+	let color = inv_color(& h.server_color);
+
+	/* There is nothing to watch in a lobby */
+	if h.spectate
+	{
+		println!("Spectator refused");
+
+		return Ok(());
+	}
+
+	let player = Player
+	{
+		peer,
+		times		: h.times,
+	};
+
+	let mut game = match fen
+	{
+		Some(fen) => GameState::from_fen(& fen),
+		None => GameState::new(),
+	};
+	game.clock = control.map(Clock::new);
+	let h = handshake(& game);
+
+	/* Find an opponent that is still there */
+	let opponent = loop
+	{
+		let opponent =
+		{
+			let mut lock = lobby_rc.lock().unwrap();
+			let lobby = lock.deref_mut();
+
+			match color
+			{
+				cnp::Color::White => lobby.black.pop_front(),
+				cnp::Color::Black => lobby.white.pop_front(),
+			}
+		};
+
+		match opponent
+		{
+			Some(opponent) =>
+			{
+				if opponent.peer.alive()
+					&& opponent.peer.write(& h).is_ok()
+				{
+					break Some(opponent);
+				}
+
+				println!("Waiting player has gone away");
+			},
+			None => break None,
+		}
+	};
+
+	let opponent = match opponent
+	{
+		Some(opponent) => opponent,
+		None =>
+		{
+			/* The player's handshake is sent once there is an
+			 * opponent */
+			let mut lock = lobby_rc.lock().unwrap();
+			let lobby = lock.deref_mut();
+
+			match color
+			{
+				cnp::Color::White => lobby.white.push_back(player),
+				cnp::Color::Black => lobby.black.push_back(player),
+			}
+
+			println!("Player waiting for an opponent");
+
+			return Ok(());
+		},
+	};
+
+	if let Err(e) = player.peer.write(& h)
+	{
+		/* Give the opponent its place back */
+		let mut lock = lobby_rc.lock().unwrap();
+		let lobby = lock.deref_mut();
+
+		match color
+		{
+			cnp::Color::White => lobby.black.push_front(opponent),
+			cnp::Color::Black => lobby.white.push_front(opponent),
+		}
+
+		return Err(e);
+	}
+
+	match color
+	{
+		cnp::Color::White => run_game(game, player, opponent),
+		cnp::Color::Black => run_game(game, opponent, player),
+	}
+
+	Ok(())
+}
+
+/* Keep the players in the lobby aware that we are still here, and drop the
+ * ones that have gone away so that nobody is paired with them */
+fn lobby_main(lobby_rc: Arc<Mutex<Lobby>>)
+{
+	loop
+	{
+		{
+			let mut lock = lobby_rc.lock().unwrap();
+			let lobby = lock.deref_mut();
+
+			for queue in [& mut lobby.white, & mut lobby.black]
+			{
+				let waiting = queue.len();
+
+				queue.retain
+				(
+					|p| p.peer.alive() && p.peer.heartbeat().is_ok()
+				);
+
+				if queue.len() < waiting
+				{
+					println!("Waiting player has gone away");
+				}
+			}
+		}
+
+		std::thread::sleep(LOBBY_INTERVAL);
+	}
+}
+
+/* Host games between pairs of clients until the process is stopped, each
+ * game starting from the given position if there is one */
+pub fn referee_main(bind: String,
+		timeout: Option<Duration>,
+		control: Option<TimeControl>,
+		fen: Option<Fen>)
+	-> std::io::Result<()>
+{
+	let fen = fen.map(Arc::new);
+
+	let listener = TcpListener::bind(bind)?;
+	println!("Referee listening on {}", listener.local_addr()?);
+
+	let lobby_rc = Arc::new(Mutex::new(Lobby
+	{
+		white		: VecDeque::new(),
+		black		: VecDeque::new(),
+	}));
+
+	(
+		|lobby_rc|
+		{
+			std::thread::spawn(move || lobby_main(lobby_rc))
+		}
+	)(lobby_rc.clone());
+
+	for stream in listener.incoming()
+	{
+		match stream
+		{
+			Ok(stream) =>
+			{
+				println!("Client connected");
+
+				let fen = fen.clone();
+				let lobby_rc = lobby_rc.clone();
+
+				std::thread::spawn
+				(
+					move || join
+					(
+						stream,
+						timeout,
+						control,
+						fen,
+						lobby_rc,
+					)
+				);
+			},
+			Err(e) => println!("Accept failed: {}", e),
+		}
+	}
+
+	Ok(())
+}
@@ -0,0 +1,382 @@
+use crate::clock::Clock;
+use crate::driver::{GameDriver, Seek, Status};
+use crate::fen;
+use crate::game::GameState;
+use crate::pgn::PgnGame;
+use crate::san::san_to_move;
+use std::cell::RefCell;
+use crate::util::*;
+
+/* A game from a PGN file, played out as far as its moves are legal */
+struct Replay
+{
+	boards		: Vec<[[cnp::Piece; 8]; 8]>,
+	moves		: Vec<String>,
+	played		: Vec<cnp::Move>,
+	/* The player who makes the first move */
+	first		: cnp::Color,
+	error		: Option<String>,
+}
+
+impl Replay
+{
+	fn new(game: & PgnGame) -> Self
+	{
+		/* A game from a set up position has it in its FEN tag */
+		let mut state = match game.tag("FEN")
+		{
+			"?" => Ok(GameState::new()),
+			position => fen::parse(position)
+				.map(|position| GameState::from_fen(& position)),
+		};
+		let mut replay = Self
+		{
+			boards		: Vec::new(),
+			moves		: Vec::new(),
+			played		: Vec::new(),
+			first		: cnp::Color::White,
+			error		: None,
+		};
+
+		let state = match state
+		{
+			Ok(ref mut state) => state,
+			Err(e) =>
+			{
+				replay.boards.push(default_board());
+				replay.error = Some(format!("Bad FEN tag, {}", e));
+
+				return replay;
+			},
+		};
+
+		replay.boards.push(state.board);
+		replay.first = copy_color(& state.turn);
+
+		for san in & game.moves
+		{
+			let m = san_to_move(san, & state.board, & state.moves);
+
+			match m
+			{
+				Ok(m) =>
+				{
+					state.play(m);
+					replay.boards.push(state.board);
+					replay.moves.push(san.clone());
+					replay.played.push(m);
+				},
+				Err(e) =>
+				{
+					let fen = state.fen();
+					let number = match fen.turn
+					{
+						cnp::Color::White =>
+							format!("{}.", fen.fullmoves),
+						cnp::Color::Black =>
+							format!("{}...", fen.fullmoves),
+					};
+
+					replay.error = Some(format!
+						("Move {} {} {}", number, san, e));
+
+					break;
+				},
+			}
+		}
+
+		replay
+	}
+}
+
+struct State
+{
+	games		: Vec<PgnGame>,
+	game		: usize,
+	replay		: Replay,
+	/* How many moves into the game the board is */
+	ply		: usize,
+}
+
+impl State
+{
+	fn report(self: & Self)
+	{
+		if let Some(ref e) = self.replay.error
+		{
+			eprintln!("Game {}: {}", self.game + 1, e);
+		}
+	}
+
+	fn open(self: & mut Self, game: usize)
+	{
+		self.game = game;
+		self.replay = Replay::new(& self.games[game]);
+		self.ply = 0;
+		self.report();
+	}
+}
+
+/* Steps through the games of a PGN file, nobody plays so there is no
+ * thread */
+pub struct ReplayDriver
+{
+	state		: RefCell<State>,
+}
+
+impl ReplayDriver
+{
+	/* Replay one of the games, the others can be gone to later */
+	pub fn new(games: Vec<PgnGame>, game: usize) -> Self
+	{
+		let replay = Replay::new(& games[game]);
+		let state = State
+		{
+			games,
+			game,
+			replay,
+			ply		: 0,
+		};
+
+		state.report();
+
+		Self
+		{
+			state		: RefCell::new(state),
+		}
+	}
+}
+
+impl GameDriver for ReplayDriver
+{
+	fn features(self: & Self) -> Vec<cnp::Features>
+	{
+		Vec::new()
+	}
+
+	fn board(self: & Self) -> [[cnp::Piece; 8]; 8]
+	{
+		let state = self.state.borrow();
+
+		state.replay.boards[state.ply]
+	}
+
+	/* The result is told in the message, so that the board can still be
+	 * seen at the end */
+	fn joever(self: & Self) -> cnp::Joever
+	{
+		cnp::Joever::Ongoing
+	}
+
+	fn color(self: & Self) -> cnp::Color
+	{
+		self.turn()
+	}
+
+	fn turn(self: & Self) -> cnp::Color
+	{
+		let state = self.state.borrow();
+
+		if state.ply % 2 == 0
+		{
+			copy_color(& state.replay.first)
+		}
+		else
+		{
+			inv_color(& state.replay.first)
+		}
+	}
+
+	fn perspective(self: & Self) -> cnp::Color
+	{
+		cnp::Color::White
+	}
+
+	fn moves(self: & Self) -> Vec<cnp::Move>
+	{
+		Vec::new()
+	}
+
+	fn get_next_move(self: & Self) -> Option<cnp::Move>
+	{
+		None
+	}
+
+	fn set_next_move(self: & Self, _m: Option<cnp::Move>)
+	{
+	}
+
+	fn resign(self: & Self)
+	{
+	}
+
+	fn offer_draw(self: & Self)
+	{
+	}
+
+	fn decline_draw(self: & Self)
+	{
+	}
+
+	fn draw_offer(self: & Self) -> Option<cnp::Color>
+	{
+		None
+	}
+
+	fn clock(self: & Self) -> Option<Clock>
+	{
+		None
+	}
+
+	fn history(self: & Self) -> Vec<String>
+	{
+		let state = self.state.borrow();
+
+		state.replay.moves[..state.ply].to_vec()
+	}
+
+	fn played(self: & Self) -> Vec<cnp::Move>
+	{
+		let state = self.state.borrow();
+
+		state.replay.played[..state.ply].to_vec()
+	}
+
+	fn seek(self: & Self, to: Seek)
+	{
+		let mut state = self.state.borrow_mut();
+		let last = state.replay.moves.len();
+		let games = state.games.len();
+
+		match to
+		{
+			Seek::First => state.ply = 0,
+			Seek::Previous => state.ply = state.ply.saturating_sub(1),
+			Seek::Next => state.ply = (state.ply + 1).min(last),
+			Seek::Last => state.ply = last,
+			Seek::PreviousGame if state.game > 0 =>
+			{
+				let game = state.game - 1;
+				state.open(game);
+			},
+			Seek::NextGame if state.game + 1 < games =>
+			{
+				let game = state.game + 1;
+				state.open(game);
+			},
+			_ => (),
+		}
+	}
+
+	/* Nothing can be done to the game but look at it */
+	fn spectating(self: & Self) -> bool
+	{
+		true
+	}
+
+	fn status(self: & Self) -> Status
+	{
+		Status::Connected
+	}
+
+	fn message(self: & Self) -> String
+	{
+		let state = self.state.borrow();
+		let game = & state.games[state.game];
+		let last = state.replay.moves.len();
+
+		let mut message = format!
+		(
+			"Game {} of {}, {} - {}",
+			state.game + 1,
+			state.games.len(),
+			game.tag("White"),
+			game.tag("Black"),
+		);
+
+		if state.ply < last
+		{
+			message.push_str
+				(& format!(", move {} of {}", state.ply, last));
+		}
+		else if let Some(ref e) = state.replay.error
+		{
+			message = e.clone();
+		}
+		else
+		{
+			message.push_str(& format!(", {}", game.result));
+		}
+
+		message
+	}
+
+	fn quit(self: & mut Self)
+	{
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::pgn;
+
+	fn replay(text: & str) -> Replay
+	{
+		Replay::new(& pgn::parse(text).unwrap()[0])
+	}
+
+	#[test]
+	fn plays_the_moves()
+	{
+		let r = replay("1. e4 e5 2. Nf3 Nc6 3. Bb5 *");
+
+		assert!(r.error.is_none());
+		assert_eq!(r.moves, ["e4", "e5", "Nf3", "Nc6", "Bb5"]);
+		assert_eq!(r.played.len(), 5);
+		assert_eq!(r.boards.len(), 6);
+		assert!(r.boards[0] == default_board());
+		assert_eq!(r.boards[5][3][1], cnp::Piece::WhiteBishop);
+		assert_eq!(r.first, cnp::Color::White);
+	}
+
+	/* The game stops at an illegal move, and the error tells which */
+	#[test]
+	fn illegal_move()
+	{
+		let r = replay("1. e4 e5 2. Ke3 Nc6 *");
+
+		assert_eq!(r.error.as_deref(), Some("Move 2. Ke3 is illegal"));
+		assert_eq!(r.moves, ["e4", "e5"]);
+		assert_eq!(r.boards.len(), 3);
+
+		let r = replay("1. e4 e5 2. Nf3 Nf3 *");
+
+		assert_eq!(r.error.as_deref(), Some("Move 2... Nf3 is illegal"));
+		assert_eq!(r.moves, ["e4", "e5", "Nf3"]);
+	}
+
+	#[test]
+	fn set_up_position()
+	{
+		let r = replay("[SetUp \"1\"]\n\
+			[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 12\"]\n\n\
+			12... Kd7 13. e4 Ke6 14. Ke2 Ke5 15. Kd5 *");
+
+		assert_eq!(r.first, cnp::Color::Black);
+		assert_eq!(r.boards[0][0][4], cnp::Piece::BlackKing);
+		assert_eq!(r.moves, ["Kd7", "e4", "Ke6", "Ke2", "Ke5"]);
+		assert_eq!(r.error.as_deref(), Some("Move 15. Kd5 is illegal"));
+	}
+
+	#[test]
+	fn bad_fen()
+	{
+		let r = replay("[FEN \"4k3/8/8 w - - 0 1\"]\n\n1. e4 *");
+
+		assert!(r.error.unwrap().starts_with("Bad FEN tag, "));
+		assert!(r.moves.is_empty());
+		assert_eq!(r.boards.len(), 1);
+	}
+}
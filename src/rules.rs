@@ -0,0 +1,409 @@
+use crate::util::*;
+
+/* The directions that pieces move in, the king and knight take a single
+ * step and the others slide along the line */
+const ROOK_LINES: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const BISHOP_LINES: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, -1), (-1, 1)];
+const ALL_LINES: [(i32, i32); 8] =
+[
+	(1, 0), (0, 1), (-1, 0), (0, -1),
+	(1, 1), (1, -1), (-1, -1), (-1, 1),
+];
+const KNIGHT_STEPS: [(i32, i32); 8] =
+[
+	(1, 2), (2, 1), (2, -1), (1, -2),
+	(-1, -2), (-2, -1), (-2, 1), (-1, 2),
+];
+
+fn on_board(x: i32, y: i32) -> bool
+{
+	(0..8).contains(& x) && (0..8).contains(& y)
+}
+
+fn new_move(x: usize, y: usize, end_x: i32, end_y: i32) -> cnp::Move
+{
+	cnp::Move
+	{
+		start_x		: x,
+		start_y		: y,
+		end_x		: end_x as usize,
+		end_y		: end_y as usize,
+		promotion	: cnp::Piece::None,
+	}
+}
+
+/* The moves of a piece that steps or slides in the given directions,
+ * stopping at the first piece in the way and taking it if it is the
+ * opponent's */
+fn piece_moves(board: & [[cnp::Piece; 8]; 8],
+		x: usize,
+		y: usize,
+		directions: & [(i32, i32)],
+		slides: bool,
+		moves: & mut Vec<cnp::Move>)
+{
+	let color = piece_color(& board[y][x]);
+
+	for (dx, dy) in directions
+	{
+		let (mut end_x, mut end_y) = (x as i32 + dx, y as i32 + dy);
+
+		while on_board(end_x, end_y)
+		{
+			let target = board[end_y as usize][end_x as usize];
+
+			if target != cnp::Piece::None && piece_color(& target) == color
+			{
+				break;
+			}
+
+			moves.push(new_move(x, y, end_x, end_y));
+
+			if target != cnp::Piece::None || !slides
+			{
+				break;
+			}
+
+			end_x += dx;
+			end_y += dy;
+		}
+	}
+}
+
+/* The moves of a pawn, one for each piece it can become when it reaches the
+ * last rank */
+fn pawn_moves(board: & [[cnp::Piece; 8]; 8],
+		x: usize,
+		y: usize,
+		turn: & cnp::Color,
+		en_passant: Option<(usize, usize)>,
+		moves: & mut Vec<cnp::Move>)
+{
+	/* White pawns move towards the top of the board */
+	let (dy, start) = match turn
+	{
+		cnp::Color::White => (-1, 6),
+		cnp::Color::Black => (1, 1),
+	};
+	let ahead = y as i32 + dy;
+	let mut ends = Vec::new();
+
+	if board[ahead as usize][x] == cnp::Piece::None
+	{
+		ends.push((x as i32, ahead));
+
+		let two = ahead + dy;
+		if y == start && board[two as usize][x] == cnp::Piece::None
+		{
+			ends.push((x as i32, two));
+		}
+	}
+
+	for end_x in [x as i32 - 1, x as i32 + 1]
+	{
+		if !on_board(end_x, ahead)
+		{
+			continue;
+		}
+
+		let end = (end_x as usize, ahead as usize);
+		let target = board[end.1][end.0];
+
+		if piece_color(& target) == Some(inv_color(turn))
+			|| en_passant == Some(end)
+		{
+			ends.push((end_x, ahead));
+		}
+	}
+
+	for (end_x, end_y) in ends
+	{
+		let m = new_move(x, y, end_x, end_y);
+
+		if end_y != 0 && end_y != 7
+		{
+			moves.push(m);
+			continue;
+		}
+
+		for kind in
+		[
+			PieceKind::Queen,
+			PieceKind::Rook,
+			PieceKind::Bishop,
+			PieceKind::Knight,
+		]
+		{
+			moves.push(cnp::Move
+			{
+				promotion	: piece_from_kind(turn, kind),
+				..m
+			});
+		}
+	}
+}
+
+/* Castling, the king may not castle out of or through check, and the
+ * squares between it and the rook must be empty */
+fn castling_moves(board: & [[cnp::Piece; 8]; 8],
+		turn: & cnp::Color,
+		castling: & str,
+		moves: & mut Vec<cnp::Move>)
+{
+	let (y, rights) = match turn
+	{
+		cnp::Color::White => (7, ['K', 'Q']),
+		cnp::Color::Black => (0, ['k', 'q']),
+	};
+	let opponent = inv_color(turn);
+
+	if board[y][4] != piece_from_kind(turn, PieceKind::King)
+		|| is_attacked(board, 4, y as i32, & opponent)
+	{
+		return;
+	}
+
+	for (right, rook_x, between, dx) in
+	[
+		(rights[0], 7, 5..7, 1),
+		(rights[1], 0, 1..4, -1),
+	]
+	{
+		if !castling.contains(right)
+			|| board[y][rook_x] != piece_from_kind(turn, PieceKind::Rook)
+			|| between
+				.clone()
+				.any(|x| board[y][x] != cnp::Piece::None)
+			|| is_attacked(board, 4 + dx, y as i32, & opponent)
+		{
+			continue;
+		}
+
+		moves.push(new_move(4, y, 4 + 2 * dx, y as i32));
+	}
+}
+
+/* The legal moves of the player to move, for positions that the rules
+ * engine can not be set up in. The castling rights are written as in FEN,
+ * and the en passant square is the one that a pawn passed over. */
+pub fn legal_moves(board: & [[cnp::Piece; 8]; 8],
+		turn: & cnp::Color,
+		castling: & str,
+		en_passant: Option<(usize, usize)>)
+	-> Vec<cnp::Move>
+{
+	let mut moves = Vec::new();
+
+	for y in 0..8
+	{
+		for x in 0..8
+		{
+			let piece = board[y][x];
+
+			if piece_color(& piece).as_ref() != Some(turn)
+			{
+				continue;
+			}
+
+			if piece_is_kind(& piece, PieceKind::Pawn)
+			{
+				pawn_moves(board, x, y, turn, en_passant, & mut moves);
+			}
+			else if piece_is_kind(& piece, PieceKind::Knight)
+			{
+				piece_moves
+					(board, x, y, & KNIGHT_STEPS, false, & mut moves);
+			}
+			else if piece_is_kind(& piece, PieceKind::Bishop)
+			{
+				piece_moves
+					(board, x, y, & BISHOP_LINES, true, & mut moves);
+			}
+			else if piece_is_kind(& piece, PieceKind::Rook)
+			{
+				piece_moves
+					(board, x, y, & ROOK_LINES, true, & mut moves);
+			}
+			else if piece_is_kind(& piece, PieceKind::Queen)
+			{
+				piece_moves
+					(board, x, y, & ALL_LINES, true, & mut moves);
+			}
+			else
+			{
+				piece_moves
+					(board, x, y, & ALL_LINES, false, & mut moves);
+			}
+		}
+	}
+
+	castling_moves(board, turn, castling, & mut moves);
+
+	/* A move may not leave the king in check */
+	moves.retain(|m| !in_check(& apply_move(board, m), turn));
+
+	moves
+}
+
+/* Take away the castling rights that a move loses, which is when
+ * something moves from or to the square of a king or rook */
+pub fn lose_castling(castling: & mut String, m: & cnp::Move)
+{
+	for square in [(m.start_x, m.start_y), (m.end_x, m.end_y)]
+	{
+		let lost = match square
+		{
+			(4, 7) => "KQ",
+			(7, 7) => "K",
+			(0, 7) => "Q",
+			(4, 0) => "kq",
+			(7, 0) => "k",
+			(0, 0) => "q",
+			_ => "",
+		};
+
+		castling.retain(|c| !lost.contains(c));
+	}
+
+	if castling.is_empty()
+	{
+		castling.push('-');
+	}
+}
+
+/* The square that a pawn which has just moved two squares passed over */
+pub fn en_passant_after(board: & [[cnp::Piece; 8]; 8], m: & cnp::Move)
+	-> Option<(usize, usize)>
+{
+	if piece_is_kind(& board[m.start_y][m.start_x], PieceKind::Pawn)
+		&& m.start_y.abs_diff(m.end_y) == 2
+	{
+		Some((m.start_x, (m.start_y + m.end_y) / 2))
+	}
+	else
+	{
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::fen;
+
+	/* Count the positions that the legal moves lead to, depth moves
+	 * ahead */
+	fn perft(board: & [[cnp::Piece; 8]; 8],
+			turn: & cnp::Color,
+			castling: & str,
+			en_passant: Option<(usize, usize)>,
+			depth: u32)
+		-> u64
+	{
+		let moves = legal_moves(board, turn, castling, en_passant);
+
+		if depth == 1
+		{
+			return moves.len() as u64;
+		}
+
+		moves
+			.iter()
+			.map
+			(
+				|m|
+				{
+					let mut castling = String::from(castling);
+					lose_castling(& mut castling, m);
+
+					perft
+					(
+						& apply_move(board, m),
+						& inv_color(turn),
+						& castling,
+						en_passant_after(board, m),
+						depth - 1,
+					)
+				}
+			)
+			.sum()
+	}
+
+	fn perft_fen(s: & str, depth: u32) -> u64
+	{
+		let fen = fen::parse(s).unwrap();
+
+		perft(& fen.board, & fen.turn, & fen.castling, fen.en_passant, depth)
+	}
+
+	#[test]
+	fn standard_position()
+	{
+		let s = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+		assert_eq!(perft_fen(s, 1), 20);
+		assert_eq!(perft_fen(s, 2), 400);
+		assert_eq!(perft_fen(s, 3), 8902);
+	}
+
+	/* Castling, promotions and pins */
+	#[test]
+	fn kiwipete()
+	{
+		let s = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R \
+			w KQkq - 0 1";
+
+		assert_eq!(perft_fen(s, 1), 48);
+		assert_eq!(perft_fen(s, 2), 2039);
+		assert_eq!(perft_fen(s, 3), 97862);
+	}
+
+	/* En passant captures that would leave the king in check */
+	#[test]
+	fn en_passant_pins()
+	{
+		let s = "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1";
+
+		assert_eq!(perft_fen(s, 1), 14);
+		assert_eq!(perft_fen(s, 2), 191);
+		assert_eq!(perft_fen(s, 3), 2812);
+		assert_eq!(perft_fen(s, 4), 43238);
+	}
+
+	#[test]
+	fn castling_rights()
+	{
+		let mut castling = String::from("KQkq");
+		let rook = cnp::Move
+		{
+			start_x		: 7,
+			start_y		: 7,
+			end_x		: 7,
+			end_y		: 4,
+			promotion	: cnp::Piece::None,
+		};
+		let king = cnp::Move
+		{
+			start_x		: 4,
+			start_y		: 0,
+			end_x		: 4,
+			end_y		: 1,
+			promotion	: cnp::Piece::None,
+		};
+
+		lose_castling(& mut castling, & rook);
+		assert_eq!(castling, "Qkq");
+
+		lose_castling(& mut castling, & king);
+		assert_eq!(castling, "Q");
+
+		/* Taking the rook also takes the right */
+		lose_castling
+		(
+			& mut castling,
+			& cnp::Move { end_x: 0, end_y: 7, ..king },
+		);
+		assert_eq!(castling, "-");
+	}
+}
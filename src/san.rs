@@ -0,0 +1,342 @@
+use crate::fen::square_name;
+use crate::util::*;
+
+/* Find the other moves that take the same kind of piece to the same square,
+ * which the move has to be told apart from */
+fn ambiguous<'a>(board: & [[cnp::Piece; 8]; 8],
+		m: & cnp::Move,
+		moves: & 'a [cnp::Move])
+	-> Vec<& 'a cnp::Move>
+{
+	let piece = board[m.start_y][m.start_x];
+
+	moves
+		.iter()
+		.filter
+		(
+			|n|
+			board[n.start_y][n.start_x] == piece
+				&& (n.end_x, n.end_y) == (m.end_x, m.end_y)
+				&& (n.start_x, n.start_y)
+					!= (m.start_x, m.start_y)
+		)
+		.collect()
+}
+
+/* Write a move in standard algebraic notation, such as Nbxd7+ or e8=Q#. The
+ * board and moves are those from before the move, and after is the moves
+ * that the opponent has once it is made. */
+pub fn move_to_san(board: & [[cnp::Piece; 8]; 8],
+		m: & cnp::Move,
+		moves: & [cnp::Move],
+		after: & [cnp::Move])
+	-> String
+{
+	let piece = board[m.start_y][m.start_x];
+	let pawn = piece_is_kind(& piece, PieceKind::Pawn);
+	let square = square_name(m.start_x, m.start_y);
+	let mut s = String::new();
+
+	if piece_is_kind(& piece, PieceKind::King)
+		&& m.start_x.abs_diff(m.end_x) == 2
+	{
+		if m.end_x > m.start_x
+		{
+			s.push_str("O-O");
+		}
+		else
+		{
+			s.push_str("O-O-O");
+		}
+	}
+	else
+	{
+		/* Pawns only move sideways when they capture, which may be
+		 * en passant onto an empty square */
+		let capture = board[m.end_y][m.end_x] != cnp::Piece::None
+			|| (pawn && m.start_x != m.end_x);
+
+		if pawn
+		{
+			if capture
+			{
+				s.push_str(& square[..1]);
+			}
+		}
+		else
+		{
+			s.push(piece_to_char(& piece).to_ascii_uppercase());
+
+			/* Name the file of the piece if that tells it apart,
+			 * then the rank, then both */
+			let others = ambiguous(board, m, moves);
+
+			if others.is_empty()
+			{
+				/* The piece is the only one that can go
+				 * there */
+			}
+			else if others.iter().all(|n| n.start_x != m.start_x)
+			{
+				s.push_str(& square[..1]);
+			}
+			else if others.iter().all(|n| n.start_y != m.start_y)
+			{
+				s.push_str(& square[1..]);
+			}
+			else
+			{
+				s.push_str(& square);
+			}
+		}
+
+		if capture
+		{
+			s.push('x');
+		}
+
+		s.push_str(& square_name(m.end_x, m.end_y));
+
+		if m.promotion != cnp::Piece::None
+		{
+			s.push('=');
+			s.push(piece_to_char(& m.promotion).to_ascii_uppercase());
+		}
+	}
+
+	if let Some(c) = piece_color(& piece)
+	{
+		if in_check(& apply_move(board, m), & inv_color(& c))
+		{
+			if after.is_empty()
+			{
+				s.push('#');
+			}
+			else
+			{
+				s.push('+');
+			}
+		}
+	}
+
+	s
+}
+
+/* Find the legal move that a move in standard algebraic notation stands
+ * for. Some leeway is given in how it is written, checks and captures need
+ * not be marked and castling may be written with zeros. */
+pub fn san_to_move(s: & str,
+		board: & [[cnp::Piece; 8]; 8],
+		moves: & [cnp::Move])
+	-> Result<cnp::Move, & 'static str>
+{
+	let s = s.trim_end_matches(['+', '#']);
+
+	let castle = match s
+	{
+		"O-O" | "0-0" => Some(2),
+		"O-O-O" | "0-0-0" => Some(-2),
+		_ => None,
+	};
+
+	let found: Vec<& cnp::Move> = if let Some(dx) = castle
+	{
+		moves
+			.iter()
+			.filter
+			(
+				|m|
+				piece_is_kind
+					(& board[m.start_y][m.start_x], PieceKind::King)
+					&& m.end_x as i32 - m.start_x as i32 == dx
+			)
+			.collect()
+	}
+	else
+	{
+		let mut chars: Vec<char> = s
+			.chars()
+			.filter(|c| !"x:-=".contains(* c))
+			.collect();
+
+		let kind = |c| match c
+		{
+			'K' => Some(PieceKind::King),
+			'Q' => Some(PieceKind::Queen),
+			'R' => Some(PieceKind::Rook),
+			'B' => Some(PieceKind::Bishop),
+			'N' => Some(PieceKind::Knight),
+			_ => None,
+		};
+
+		/* A promotion comes after the square, a piece before it */
+		let mut promotion = None;
+		if chars.len() >= 3
+		{
+			if let Some(k) = kind(chars[chars.len() - 1])
+			{
+				promotion = Some(k);
+				chars.pop();
+			}
+		}
+
+		let piece = match chars.first().and_then(|c| kind(* c))
+		{
+			Some(k) =>
+			{
+				chars.remove(0);
+				k
+			},
+			None => PieceKind::Pawn,
+		};
+
+		if chars.len() < 2
+		{
+			return Err("is not a move");
+		}
+
+		let square: String = chars.split_off(chars.len() - 2)
+			.into_iter()
+			.collect();
+
+		/* What is left tells the file or rank of the piece */
+		moves
+			.iter()
+			.filter
+			(
+				|m|
+				{
+					let start = square_name(m.start_x, m.start_y);
+
+					piece_is_kind
+						(& board[m.start_y][m.start_x], piece)
+						&& square_name(m.end_x, m.end_y)
+							== square
+						&& chars
+							.iter()
+							.all(|c| start.contains(* c))
+						&& match promotion
+						{
+							Some(k) => piece_is_kind
+								(& m.promotion, k),
+							None => m.promotion
+								== cnp::Piece::None,
+						}
+				}
+			)
+			.collect()
+	};
+
+	match found.as_slice()
+	{
+		[m] => Ok(** m),
+		[] => Err("is illegal"),
+		_ => Err("is ambiguous"),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::fen;
+	use crate::rules::legal_moves;
+
+	/* The board and legal moves of a position */
+	fn position(s: & str) -> ([[cnp::Piece; 8]; 8], Vec<cnp::Move>)
+	{
+		let fen = fen::parse(s).unwrap();
+		let moves = legal_moves
+			(& fen.board, & fen.turn, & fen.castling, fen.en_passant);
+
+		(fen.board, moves)
+	}
+
+	/* Write a move in SAN after reading it back, along with the moves
+	 * of the opponent after it */
+	fn round_trip(s: & str, san: & str) -> String
+	{
+		let fen = fen::parse(s).unwrap();
+		let (board, moves) = position(s);
+		let m = san_to_move(san, & board, & moves).unwrap();
+		let after = apply_move(& board, & m);
+		let after_moves = legal_moves
+			(& after, & inv_color(& fen.turn), "-", None);
+
+		move_to_san(& board, & m, & moves, & after_moves)
+	}
+
+	#[test]
+	fn disambiguation()
+	{
+		/* Knights on b1 and f3 can both go to d2, rooks on a1 and a5
+		 * to a3, and queens on e4, h4 and h1 to e1 */
+		let s = "2k5/8/8/R7/4Q2Q/3K1N2/8/RN5Q w - - 0 1";
+
+		assert_eq!(round_trip(s, "Nbd2"), "Nbd2");
+		assert_eq!(round_trip(s, "Nfd2"), "Nfd2");
+		assert_eq!(round_trip(s, "R1a3"), "R1a3");
+		assert_eq!(round_trip(s, "R5a3"), "R5a3");
+		assert_eq!(round_trip(s, "Qee1"), "Qee1");
+		assert_eq!(round_trip(s, "Q1e1"), "Q1e1");
+		assert_eq!(round_trip(s, "Qh4e1"), "Qh4e1");
+
+		let (board, moves) = position(s);
+
+		assert_eq!(san_to_move("Nd2", & board, & moves), Err("is ambiguous"));
+		assert_eq!(san_to_move("Qhe1", & board, & moves), Err("is ambiguous"));
+	}
+
+	#[test]
+	fn check_and_mate()
+	{
+		let s = "6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1";
+
+		assert_eq!(round_trip(s, "Ra8"), "Ra8#");
+		assert_eq!(round_trip(s, "Ra7"), "Ra7");
+
+		let s = "6k1/5pp1/8/8/8/8/8/R5K1 w - - 0 1";
+
+		assert_eq!(round_trip(s, "Ra8"), "Ra8+");
+	}
+
+	#[test]
+	fn castling_promotion_and_en_passant()
+	{
+		let s = "r3k2r/1P6/8/3Pp3/8/8/8/R3K2R w KQkq e6 0 1";
+
+		assert_eq!(round_trip(s, "O-O"), "O-O");
+		assert_eq!(round_trip(s, "O-O-O"), "O-O-O");
+		assert_eq!(round_trip(s, "dxe6"), "dxe6");
+		assert_eq!(round_trip(s, "b8=N"), "b8=N");
+		assert_eq!(round_trip(s, "bxa8=Q+"), "bxa8=Q+");
+
+		let (board, moves) = position(s);
+		let m = san_to_move("dxe6", & board, & moves).unwrap();
+
+		assert_eq!(apply_move(& board, & m)[3][4], cnp::Piece::None);
+	}
+
+	#[test]
+	fn leniency()
+	{
+		let s = "r3k2r/1P6/8/3Pp3/8/8/8/R3K2R w KQkq e6 0 1";
+		let (board, moves) = position(s);
+		let read = |san| san_to_move(san, & board, & moves);
+
+		/* Zeros for castling, missing or extra marks, and promotions
+		 * without the = */
+		assert_eq!(read("0-0"), read("O-O"));
+		assert_eq!(read("0-0-0"), read("O-O-O"));
+		assert_eq!(read("de6"), read("dxe6"));
+		assert_eq!(read("d5xe6"), read("dxe6"));
+		assert_eq!(read("Ra1-d1"), read("Rd1"));
+		assert_eq!(read("Rd1+"), read("Rd1"));
+		assert_eq!(read("bxa8Q"), read("bxa8=Q"));
+		assert!(read("O-O").is_ok());
+
+		assert_eq!(read("b8"), Err("is illegal"));
+		assert_eq!(read("Ke3"), Err("is illegal"));
+		assert_eq!(read("x"), Err("is not a move"));
+	}
+}
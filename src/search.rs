@@ -0,0 +1,390 @@
+extern crate scrappy_chess;
+
+use self::scrappy_chess::chess;
+use crate::util::*;
+use std::time::{Duration, Instant};
+
+/* Scores are in centipawns from the point of view of the player to move,
+ * mates are scored as MATE less the number of plies it takes to get there */
+pub const MATE: i32 = 1_000_000;
+const INFINITY: i32 = 2 * MATE;
+/* Scores this close to MATE are forced mates */
+pub const MATE_BOUND: i32 = MATE - 1000;
+/* How many captures to follow past the end of the search */
+const CAPTURE_DEPTH: u32 = 4;
+/* How many nodes to search between looks at the clock and the stop flag */
+const CHECK_INTERVAL: u64 = 1024;
+/* How deep to go when there is no depth limit */
+const MAX_DEPTH: u32 = 64;
+
+/* How much effort to put into a search, it goes one ply deeper at a time
+ * until the depth is reached or a budget runs out */
+#[derive(Clone, Copy)]
+pub struct Limits
+{
+	pub depth	: Option<u32>,
+	pub nodes	: Option<u64>,
+	pub time	: Option<Duration>,
+}
+
+pub struct SearchResult
+{
+	pub best	: chess::Move,
+	pub score	: i32,
+	pub depth	: u32,
+	pub nodes	: u64,
+}
+
+fn piece_kind(p: & cnp::Piece) -> Option<PieceKind>
+{
+	[
+		PieceKind::King,
+		PieceKind::Queen,
+		PieceKind::Rook,
+		PieceKind::Bishop,
+		PieceKind::Knight,
+		PieceKind::Pawn,
+	]
+		.into_iter()
+		.find(|k| piece_is_kind(p, *k))
+}
+
+fn piece_value(k: PieceKind) -> i32
+{
+	match k
+	{
+		PieceKind::King		=> 0,
+		PieceKind::Queen	=> 900,
+		PieceKind::Rook		=> 500,
+		PieceKind::Bishop	=> 330,
+		PieceKind::Knight	=> 320,
+		PieceKind::Pawn		=> 100,
+	}
+}
+
+/* A bonus for where a piece stands, pieces want to be in the center and
+ * pawns want to advance while the king stays out of the way */
+fn piece_bonus(k: PieceKind, c: & cnp::Color, x: usize, y: usize) -> i32
+{
+	let center = 14
+		- (2 * x as i32 - 7).abs()
+		- (2 * y as i32 - 7).abs();
+
+	/* How far the piece is from its own side of the board */
+	let advance = match c
+	{
+		cnp::Color::White => 7 - y as i32,
+		cnp::Color::Black => y as i32,
+	};
+
+	match k
+	{
+		PieceKind::King		=> -center * 2,
+		PieceKind::Queen	=> center,
+		PieceKind::Rook		=> 0,
+		PieceKind::Bishop	=> center * 2,
+		PieceKind::Knight	=> center * 4,
+		PieceKind::Pawn		=> (advance - 1) * 8 + center / 2,
+	}
+}
+
+/* Score a position by material and piece placement */
+pub fn evaluate(board: & [[cnp::Piece; 8]; 8], turn: & cnp::Color) -> i32
+{
+	let mut score = 0;
+
+	for y in 0..8
+	{
+		for x in 0..8
+		{
+			let p = & board[y][x];
+			let (k, c) = match (piece_kind(p), piece_color(p))
+			{
+				(Some(k), Some(c)) => (k, c),
+				_ => continue,
+			};
+
+			let value = piece_value(k) + piece_bonus(k, & c, x, y);
+
+			if c == * turn
+			{
+				score += value;
+			}
+			else
+			{
+				score -= value;
+			}
+		}
+	}
+
+	score
+}
+
+/* Put the moves that are most likely to be good first, which makes the
+ * search cut off more of the others: promotions, then captures of valuable
+ * pieces by cheap ones */
+fn order_moves(board: & [[cnp::Piece; 8]; 8], mut c_moves: Vec<chess::Move>)
+	-> Vec<chess::Move>
+{
+	c_moves.sort_by_cached_key
+	(
+		|cm|
+		{
+			let m = translate_move(board, cm);
+			let mut score = 0;
+
+			if let Some(k) = piece_kind(& m.promotion)
+			{
+				score += piece_value(k) * 10;
+			}
+
+			if let Some(k) = piece_kind(& board[m.end_y][m.end_x])
+			{
+				score += piece_value(k) * 10;
+
+				if let Some(a) = piece_kind
+					(& board[m.start_y][m.start_x])
+				{
+					score -= piece_value(a);
+				}
+			}
+
+			-score
+		}
+	);
+
+	c_moves
+}
+
+fn is_capture(board: & [[cnp::Piece; 8]; 8], cm: & chess::Move) -> bool
+{
+	let m = translate_move(board, cm);
+
+	board[m.end_y][m.end_x] != cnp::Piece::None
+		|| m.promotion != cnp::Piece::None
+}
+
+struct Searcher<'a, F>
+	where F: Fn() -> bool
+{
+	moveset		: & 'a chess::MoveSet,
+	limits		: & 'a Limits,
+	stop		: F,
+	start		: Instant,
+	nodes		: u64,
+	aborted		: bool,
+}
+
+impl<'a, F> Searcher<'a, F>
+	where F: Fn() -> bool
+{
+	/* Count a node and check if the search has to end */
+	fn visit(self: & mut Self) -> bool
+	{
+		self.nodes += 1;
+
+		if let Some(nodes) = self.limits.nodes
+		{
+			if self.nodes >= nodes
+			{
+				self.aborted = true;
+			}
+		}
+
+		if self.nodes % CHECK_INTERVAL == 0
+		{
+			if let Some(time) = self.limits.time
+			{
+				if self.start.elapsed() >= time
+				{
+					self.aborted = true;
+				}
+			}
+
+			if (self.stop)()
+			{
+				self.aborted = true;
+			}
+		}
+
+		self.aborted
+	}
+
+	/* Alpha-beta search of a position, returns the score for the player
+	 * to move */
+	fn negamax(self: & mut Self,
+			c_state: & chess::ChessState,
+			turn: & cnp::Color,
+			depth: u32,
+			ply: i32,
+			mut alpha: i32,
+			beta: i32)
+		-> i32
+	{
+		if self.visit()
+		{
+			return 0;
+		}
+
+		let board = translate_board(c_state);
+		let c_moves = c_state.get_moves(self.moveset);
+
+		if c_moves.is_empty()
+		{
+			if in_check(& board, turn)
+			{
+				return -MATE + ply;
+			}
+			else
+			{
+				return 0;
+			}
+		}
+
+		if insufficient_material(& board)
+		{
+			return 0;
+		}
+
+		/* Past the end of the search only captures are followed, so
+		 * that a position is not scored in the middle of an
+		 * exchange */
+		let captures = depth <= CAPTURE_DEPTH;
+
+		if captures
+		{
+			let stand_pat = evaluate(& board, turn);
+
+			if depth == 0 || stand_pat >= beta
+			{
+				return stand_pat.min(beta);
+			}
+
+			alpha = alpha.max(stand_pat);
+		}
+
+		let next = inv_color(turn);
+
+		for cm in order_moves(& board, c_moves)
+		{
+			if captures && !is_capture(& board, & cm)
+			{
+				continue;
+			}
+
+			let score = -self.negamax
+			(
+				& cm.result,
+				& next,
+				depth - 1,
+				ply + 1,
+				-beta,
+				-alpha,
+			);
+
+			if self.aborted
+			{
+				return 0;
+			}
+
+			if score >= beta
+			{
+				return beta;
+			}
+
+			alpha = alpha.max(score);
+		}
+
+		alpha
+	}
+}
+
+/* Find the best move for the player to move, returns None if there are no
+ * moves. Stop is called now and then, and ends the search early if it
+ * returns true. */
+pub fn search<F>(c_state: & chess::ChessState,
+			turn: & cnp::Color,
+			limits: & Limits,
+			stop: F)
+	-> Option<SearchResult>
+	where F: Fn() -> bool
+{
+	let moveset = chess::MoveSet::new();
+	let board = translate_board(c_state);
+	let mut c_moves = order_moves(& board, c_state.get_moves(& moveset));
+
+	let mut result = SearchResult
+	{
+		best		: copy_move(c_moves.first()?),
+		score		: 0,
+		depth		: 0,
+		nodes		: 0,
+	};
+
+	let mut searcher = Searcher
+	{
+		moveset		: & moveset,
+		limits,
+		stop,
+		start		: Instant::now(),
+		nodes		: 0,
+		aborted		: false,
+	};
+
+	let next = inv_color(turn);
+
+	/* The search works in plies, the captures that follow the last one
+	 * are searched on top of that */
+	for depth in 1..=limits.depth.unwrap_or(MAX_DEPTH).max(1)
+	{
+		let mut alpha = -INFINITY;
+		let mut best = 0;
+
+		for (i, cm) in c_moves.iter().enumerate()
+		{
+			let score = -searcher.negamax
+			(
+				& cm.result,
+				& next,
+				depth - 1 + CAPTURE_DEPTH,
+				1,
+				-INFINITY,
+				-alpha,
+			);
+
+			if searcher.aborted
+			{
+				break;
+			}
+
+			if score > alpha
+			{
+				alpha = score;
+				best = i;
+			}
+		}
+
+		/* An unfinished iteration can not be trusted */
+		if searcher.aborted
+		{
+			break;
+		}
+
+		/* Search the best move first in the next iteration */
+		let cm = c_moves.remove(best);
+		c_moves.insert(0, cm);
+
+		result.best = copy_move(& c_moves[0]);
+		result.score = alpha;
+		result.depth = depth;
+
+		if alpha.abs() >= MATE_BOUND
+		{
+			break;
+		}
+	}
+
+	result.nodes = searcher.nodes;
+
+	Some(result)
+}
@@ -1,100 +1,202 @@
-extern crate scrappy_chess;
 extern crate serde;
 
-use crate::driver::GameDriver;
-use self::scrappy_chess::chess;
+use crate::clock::{Clock, ClockSync};
+use crate::driver::{GameDriver, Status};
+use crate::game::GameState;
+use crate::handshake::ClientHandshake;
+use std::io;
 use std::net::TcpStream;
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::net;
+use crate::takeback::{self, Message, Takeback};
 use crate::util::*;
 
+/* How often the player is checked on while the client waits for an
+ * answer */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/* How many messages a spectator may fall behind before it is dropped */
+const SPECTATOR_BACKLOG: usize = 64;
+/* How long a spectator may take to take in a message */
+const SPECTATOR_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/* The queue of messages for a spectator, which a thread of its own
+ * writes */
+struct Spectator
+{
+	tx		: mpsc::SyncSender<String>,
+	times		: bool,
+}
+
 struct State
 {
-	c_state		: chess::ChessState,
-	c_moveset	: chess::MoveSet,
-	c_moves		: Vec<chess::Move>,
+	game		: GameState,
 
-	features	: Vec<cnp::Features>,
-	board		: [[cnp::Piece; 8]; 8],
-	joever		: cnp::Joever,
 	color		: cnp::Color,
-	turn		: cnp::Color,
-	moves		: Vec<cnp::Move>,
 	next_move	: Option<cnp::Move>,
-
+	resign		: bool,
+	offer_draw	: bool,
+	draw_offer	: Option<cnp::Color>,
+	takebacks	: bool,
+	ask_takeback	: bool,
+	takeback	: Option<cnp::Color>,
+	answer		: Option<bool>,
+
+	started		: bool,
+	/* The token of the client that started the game, which it shows to
+	 * resume it */
+	token		: Option<String>,
+	player		: bool,
+	done		: bool,
+	last_move	: Option<cnp::Move>,
+	/* The client wants the clock times ahead of every state */
+	times		: bool,
+	spectators	: Vec<Spectator>,
 	stream		: Option<TcpStream>,
+	status		: Status,
+	responding	: bool,
 	message		: String,
 	quit		: bool,
 }
 
 impl State
 {
-	fn new() -> Self
+	fn new(game: GameState) -> Self
 	{
-		let mut state = Self
+		Self
 		{
-			c_state		: chess::ChessState::standard(),
-			c_moveset	: chess::MoveSet::new(),
-			c_moves		: Vec::new(),
+			game,
 
-			features	: vec!
-				[
-					cnp::Features::PossibleMoveGeneration,
-				],
-			board		: [[cnp::Piece::None; 8]; 8],
-			joever		: cnp::Joever::Ongoing,
 			color		: cnp::Color::White,
-			turn		: cnp::Color::White,
-			moves		: Vec::new(),
 			next_move	: None,
-
+			resign		: false,
+			offer_draw	: false,
+			draw_offer	: None,
+			takebacks	: false,
+			ask_takeback	: false,
+			takeback	: None,
+			answer		: None,
+
+			started		: false,
+			token		: None,
+			player		: false,
+			done		: false,
+			last_move	: None,
+			times		: false,
+			spectators	: Vec::new(),
 			stream		: None,
+			status		: Status::Connecting,
+			responding	: true,
 			message		: String::new(),
 			quit		: false,
-		};
+		}
+	}
 
-		state.update();
+	/* The features of the game, along with the takeback extension which
+	 * the server always understands */
+	fn features(self: & Self) -> Vec<cnp::Features>
+	{
+		let mut features = self.game.features();
+		features.push(takeback::to_feature());
 
-		state
+		features
 	}
 
-	fn pass_turn(self: & mut Self)
+	fn state_msg(self: & Self) -> cnp::ServerToClient
 	{
-		self.turn = inv_color(& self.turn);
+		let none = cnp::Move
+		{
+			start_x		: 0,
+			start_y		: 0,
+			end_x		: 0,
+			end_y		: 0,
+			promotion	: cnp::Piece::None,
+		};
+
+		cnp::ServerToClient::State
+		{
+			board		: self.game.board,
+			moves		: self.game.moves.clone(),
+			joever		: self.game.joever,
+			move_made	: self.last_move.unwrap_or(none),
+		}
 	}
 
-	fn update(self: & mut Self)
+	/* Queue a message for every spectator, after the clock times for the
+	 * ones that want them. Spectators that have gone away or fallen too
+	 * far behind are dropped, nothing is written while the state is
+	 * locked. */
+	fn broadcast(self: & mut Self, msg: & cnp::ServerToClient)
 	{
-		self.board = translate_board(& self.c_state);
+		let text = match serde_json::to_string(msg)
+		{
+			Ok(text) => text,
+			Err(_) => return,
+		};
+		let times = self.game.clock
+			.and_then(|c| serde_json::to_string(& c.to_sync()).ok());
 
-		self.c_moves = self.c_state.get_moves(& self.c_moveset);
-		self.moves = self.c_moves.iter().map(translate_move).collect();
+		self.spectators.retain
+		(
+			|s|
+			{
+				let times_sent = match times
+				{
+					Some(ref times) if s.times =>
+						s.tx.try_send(times.clone()).is_ok(),
+					_ => true,
+				};
 
-		if self.moves.len() == 0
-		{
-			self.joever = cnp::Joever::Indeterminate;
-		}
+				times_sent && s.tx.try_send(text.clone()).is_ok()
+			}
+		);
 	}
+
 }
 
 struct Server
 {
-	stream		: TcpStream,
+	peer		: net::Peer,
 	state_rc	: Arc<Mutex<State>>,
 }
 
 impl Server
 {
-	/* Receive and deserialize a data type from the client */
-	fn read<T>(self: & Self)
+	/* Receive and deserialize a data type from the client, reply tells if
+	 * the client should be answering promptly. Gives up if the client runs
+	 * out of time while we wait. */
+	fn read<T>(self: & Self, reply: bool)
 		-> Result<T, serde_json::Error>
 		where T: for<'de> serde::de::Deserialize<'de>
 	{
-		let mut de = serde_json::Deserializer
-			::from_reader(& self.stream);
+		self.peer.read_while
+		(
+			reply,
+			|quiet| self.mutate(|state| state.responding = !quiet),
+			||
+			{
+				let flagged = self.mutate
+				(
+					|state|
+					state.game.clock
+						.map_or(false, |c| c.flagged().is_some())
+				);
+
+				if flagged
+				{
+					return Err(serde_json::Error::io(io::Error::new
+					(
+						io::ErrorKind::Other,
+						"out of time",
+					)));
+				}
 
-		T::deserialize(& mut de)
+				Ok(())
+			},
+		)
 	}
 
 	/* Serialize and send a data type to the client */
@@ -102,7 +204,7 @@ impl Server
 		-> Result<(), serde_json::Error>
 		where T: serde::ser::Serialize
 	{
-		serde_json::to_writer(& self.stream, t)
+		self.peer.write(t)
 	}
 
 	/* Acquire and mutate the local state in a closure */
@@ -129,7 +231,7 @@ impl GameDriver for ServerDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 
-		state.features.clone()
+		state.features()
 	}
 
 	fn board(self: & Self) -> [[cnp::Piece; 8]; 8]
@@ -137,7 +239,7 @@ impl GameDriver for ServerDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 
-		state.board
+		state.game.board
 	}
 
 	fn joever(self: & Self) -> cnp::Joever
@@ -145,7 +247,7 @@ impl GameDriver for ServerDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 	
-		state.joever
+		state.game.joever
 	}
 
 	fn color(self: & Self) -> cnp::Color
@@ -161,7 +263,7 @@ impl GameDriver for ServerDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 	
-		copy_color(& state.turn)
+		copy_color(& state.game.turn)
 	}
 
 	fn moves(self: & Self) -> Vec<cnp::Move>
@@ -169,7 +271,7 @@ impl GameDriver for ServerDriver
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 	
-		state.moves.clone()
+		state.game.moves.clone()
 	}
 
 	fn get_next_move(self: & Self) -> Option<cnp::Move>
@@ -188,12 +290,159 @@ impl GameDriver for ServerDriver
 		state.next_move = m;
 	}
 
+	fn resign(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.game.joever == cnp::Joever::Ongoing
+		{
+			state.resign = true;
+
+			if state.game.turn != state.color
+			{
+				state.message = String
+					::from("Resigning on your turn");
+			}
+		}
+	}
+
+	fn offer_draw(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.game.joever == cnp::Joever::Ongoing
+			&& state.draw_offer != Some(copy_color(& state.color))
+		{
+			state.offer_draw = true;
+
+			if state.draw_offer.is_none()
+			{
+				state.message = String::from
+				("A draw will be offered with your next move");
+			}
+		}
+	}
+
+	fn decline_draw(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		/* The opponent is told by the next move that is sent */
+		if state.draw_offer == Some(inv_color(& state.color))
+		{
+			state.draw_offer = None;
+			state.message = String::from("Draw declined");
+		}
+	}
+
+	fn draw_offer(self: & Self) -> Option<cnp::Color>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.draw_offer.as_ref().map(copy_color)
+	}
+
+	fn clock(self: & Self) -> Option<Clock>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.clock
+	}
+
+	fn history(self: & Self) -> Vec<String>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.san.clone()
+	}
+
+	fn played(self: & Self) -> Vec<cnp::Move>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.played.clone()
+	}
+
+	fn fen(self: & Self) -> String
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.game.fen().to_string()
+	}
+
+	/* Ask the client to take back the last move of each player, which is
+	 * done on the player's turn */
+	fn undo(self: & Self)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.game.joever == cnp::Joever::Ongoing
+			&& state.takeback.is_none()
+		{
+			state.ask_takeback = true;
+
+			if state.game.turn != state.color
+			{
+				state.message = String::from
+				("A takeback will be asked for on your turn");
+			}
+		}
+	}
+
+	fn takeback_request(self: & Self) -> Option<cnp::Color>
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.takeback.as_ref().map(copy_color)
+	}
+
+	fn answer_takeback(self: & Self, accept: bool)
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.takeback == Some(inv_color(& state.color))
+		{
+			state.answer = Some(accept);
+		}
+	}
+
+	fn spectating(self: & Self) -> bool
+	{
+		false
+	}
+
+	fn status(self: & Self) -> Status
+	{
+		let mut lock = self.state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.status.clone()
+	}
+
 	fn message(self: & Self) -> String
 	{
 		let mut lock = self.state_rc.lock().unwrap();
 		let state = lock.deref_mut();
 
-		state.message.clone()
+		if !state.responding
+		{
+			String::from("Opponent not responding")
+		}
+		else
+		{
+			state.message.clone()
+		}
 	}
 
 	fn quit(self: & mut Self)
@@ -210,6 +459,7 @@ impl GameDriver for ServerDriver
 				state.stream = None;
 			}
 
+			state.spectators.clear();
 			state.quit = true;
 		}
 
@@ -220,22 +470,50 @@ impl GameDriver for ServerDriver
 	}
 }
 
-fn send_state(server: & Server, move_made: cnp::Move)
+/* Send the client a message with a new state, along with the clock times
+ * if it wants them */
+fn send_update(server: & Server, msg: & cnp::ServerToClient)
+	-> Result<(), serde_json::Error>
+{
+	let times: Option<ClockSync> = server.mutate
+	(
+		|state|
+		{
+			match state.game.clock
+			{
+				Some(clock) if state.times => Some(clock.to_sync()),
+				_ => None,
+			}
+		}
+	);
+
+	if let Some(times) = times
+	{
+		server.write(& times)?;
+	}
+
+	server.write(msg)
+}
+
+/* Send the state after a move, or after a takeback when there is no move */
+fn send_state(server: & Server, move_made: Option<cnp::Move>)
 	-> Result<(), serde_json::Error>
 {
 	let msg = server.mutate
 	(
 		|state|
-		cnp::ServerToClient::State
 		{
-			board		: state.board,
-			moves		: state.moves.clone(),
-			joever		: state.joever,
-			move_made
+			state.last_move = move_made;
+
+			/* Spectators follow every state the client is sent */
+			let msg = state.state_msg();
+			state.broadcast(& msg);
+
+			msg
 		}
 	);
 
-	server.write(& msg)?;
+	send_update(server, & msg)?;
 	println!("State sent");
 
 	Ok(())
@@ -249,236 +527,978 @@ fn send_err(server: & Server, message: & str)
 		|state|
 		cnp::ServerToClient::Error
 		{
-			board		: state.board,
-			moves		: state.moves.clone(),
-			joever		: state.joever,
+			board		: state.game.board,
+			moves		: state.game.moves.clone(),
+			joever		: state.game.joever,
 			message		: String::from(message),
 		}
 	);
 
-	server.write(& msg)?;
+	send_update(server, & msg)?;
 	println!("Error sent: {}", message);
 
 	Ok(())
 }
 
-fn send_move(server: & Server)
+fn send_resigned(server: & Server)
 	-> Result<(), serde_json::Error>
 {
-	/* Validate next move */
-	let next_move = server.mutate
+	let msg = server.mutate
 	(
 		|state|
+		cnp::ServerToClient::Resigned
 		{
-			if let Some(m) = state.next_move.take()
-			{
-				let ms = & state.c_moves;
+			board		: state.game.board,
+			joever		: state.game.joever,
+		}
+	);
 
-				if let Some(cm) = match_move(& m, ms)
-				{
-					return Some((m, cm));
-				}
-			}
+	send_update(server, & msg)?;
+	println!("Resignation sent");
 
-			None
+	Ok(())
+}
+
+fn send_draw(server: & Server)
+	-> Result<(), serde_json::Error>
+{
+	let msg = server.mutate
+	(
+		|state|
+		cnp::ServerToClient::Draw
+		{
+			board		: state.game.board,
+			moves		: state.game.moves.clone(),
 		}
 	);
 
-	if let Some((m, cm)) = next_move
-	{
-		/* Play move */
-		server.mutate
-		(
-			|state|
+	send_update(server, & msg)?;
+	println!("Draw sent");
+
+	Ok(())
+}
+
+/* Tell the client and the spectators that a player has run out of time,
+ * the reason goes along as the message of an error */
+fn send_flag(server: & Server)
+	-> Result<(), serde_json::Error>
+{
+	let msg = server.mutate
+	(
+		|state|
+		{
+			let reason = state.game.reason.unwrap_or("Out of time");
+			state.message = String::from(reason);
+
+			let msg = cnp::ServerToClient::Error
 			{
-				state.c_state = cm.result;
-				state.update();
-				state.pass_turn();
-			}
-		);
+				board		: state.game.board,
+				moves		: state.game.moves.clone(),
+				joever		: state.game.joever,
+				message		: String::from(reason),
+			};
+			state.broadcast(& msg);
+
+			msg
+		}
+	);
 
-		/* Send updated state to client */
-		send_state(server, m)?;
-	}
+	send_update(server, & msg)?;
+	println!("Flag fall sent");
 
 	Ok(())
 }
 
-fn recv_move(server: & Server)
+/* Take back the last move of each player, it is the same player's turn
+ * afterwards */
+fn take_back(state: & mut State)
+{
+	state.game.undo();
+	state.game.undo();
+
+	state.takeback = None;
+	state.draw_offer = None;
+	state.message = String::from("Takeback accepted");
+}
+
+/* Ask the client to take back a move and wait for its answer */
+fn send_takeback(server: & Server)
 	-> Result<(), serde_json::Error>
 {
-	/* Receive message from client */
-	println!("Waiting for move");
-	let msg = server.read::<cnp::ClientToServer>()?;
-	println!("Message received");
+	let ask = server.mutate
+	(
+		|state|
+		{
+			state.ask_takeback = false;
+
+			if !state.takebacks
+			{
+				state.message = String::from
+					("The opponent can not take back moves");
+			}
+			else if state.game.played.len() < 2
+			{
+				state.message = String::from
+					("There is no move to take back");
+			}
+			else
+			{
+				state.takeback = Some(copy_color(& state.color));
+				state.message = String::from("Takeback requested");
+
+				return true;
+			}
+
+			false
+		}
+	);
 
-	if let cnp::ClientToServer::Move(m) = msg
+	if !ask
 	{
-		println!("Move received");
+		return Ok(());
+	}
 
-		/* Validate received move */
-		let next_move = server.mutate
-		(
-			|state|
-			{
-				let ms = & state.c_moves;
+	server.write(& Takeback::Request)?;
+	println!("Takeback request sent");
 
-				if let Some(cm) = match_move(& m, ms)
-				{
-					return Some((m, cm));
-				}
+	/* The client's player takes their time to answer, while the
+	 * player's clock runs */
+	println!("Waiting for response");
+	let msg = match server.read::<Message<cnp::ClientToServer>>(false)
+	{
+		Ok(msg) => msg,
+		Err(e) =>
+		{
+			server.mutate(|state| state.takeback = None);
 
-				None
+			if server.mutate(|state| state.game.check_flag())
+			{
+				return send_flag(server);
 			}
-		);
 
-		if let Some((m, cm)) = next_move		
+			return Err(e);
+		},
+	};
+
+	match msg
+	{
+		Message::Takeback(Takeback::Accept) =>
+		{
+			println!("Takeback accepted");
+
+			server.mutate(take_back);
+			send_state(server, None)?;
+		},
+		Message::Takeback(Takeback::Refuse) =>
 		{
-			/* Play move */
+			println!("Takeback refused");
+
 			server.mutate
 			(
 				|state|
 				{
-					state.c_state = cm.result;
-					state.update();
-					state.pass_turn();
+					state.takeback = None;
+					state.message = String::from
+						("Takeback refused");
 				}
 			);
-
-			/* Send updated state to the client */
-			send_state(server, m)?;
-		}
-		else
-		{
-			/* Send error message */
-			send_err(server, "That move is invalid")?;
-		}
-	}
-	else
-	{
-		/* Unsupported, send error message */
-		send_err(server, "That action is not supported")?;
+		},
+		Message::Takeback(t) => return Err(takeback::unexpected(& t)),
+		Message::Protocol(_) => return Err(serde::de::Error::custom
+			("expected an answer to the takeback request")),
 	}
 
 	Ok(())
 }
 
-fn server_main(state_rc: Arc<Mutex<State>>)
+/* Handle a takeback message that the client sends on its turn */
+fn recv_takeback(server: & Server, t: Takeback)
 	-> Result<(), serde_json::Error>
 {
-	println!("Entered server_main");
-
+	match t
 	{
-		let mut lock = state_rc.lock().unwrap();
-		let state = lock.deref_mut();
-
-		state.message = String::from("Starting network game");
-	}
+		Takeback::Supported =>
+		{
+			println!("Client supports takebacks");
+			server.mutate(|state| state.takebacks = true);
 
-	/* Listen for a connection */
-	let bind_result = std::net::TcpListener::bind("0.0.0.0:8384");
-	if bind_result.is_err()
-	{
-		return Ok(());
+			return Ok(());
+		},
+		Takeback::Request => (),
+		t => return Err(takeback::unexpected(& t)),
 	}
-	let listener = bind_result.unwrap();
-	println!("Server listening");
 
-	{
-		let mut lock = state_rc.lock().unwrap();
-		let state = lock.deref_mut();
+	println!("Takeback request received");
 
-		state.message = String::from("Waiting for opponent");
-	}
-	
-	/* Accept a connection */
-	let accept_result = listener.accept();
-	if accept_result.is_err()
+	if server.mutate(|state| state.game.played.len() < 2)
 	{
-		return Ok(());
+		return send_err(server, "There is no move to take back");
 	}
-	let (stream, _addr) = accept_result.unwrap();
-	println!("Server connected");
 
-	/* Create the server struct */
-	let server = Server
-	{
-		stream,
-		state_rc,
+	server.mutate
+	(
+		|state|
+		{
+			state.takeback = Some(inv_color(& state.color));
+			state.answer = None;
+			state.message = String::from
+				("Opponent asks to take back a move");
+		}
+	);
+
+	/* Wait for the player to answer, while the client's clock runs */
+	let accept = loop
+	{
+		if server.mutate(|state| state.quit)
+		{
+			return Ok(());
+		}
+
+		if server.mutate(|state| state.game.check_flag())
+		{
+			server.mutate(|state| state.takeback = None);
+
+			return send_flag(server);
+		}
+
+		if let Some(accept) = server.mutate(|state| state.answer.take())
+		{
+			break accept;
+		}
+
+		server.peer.heartbeat()?;
+		std::thread::sleep(POLL_INTERVAL);
 	};
 
-	server.mutate
+	if accept
+	{
+		println!("Takeback accepted");
+
+		server.mutate(take_back);
+		send_state(server, None)?;
+	}
+	else
+	{
+		println!("Takeback refused");
+
+		server.mutate
+		(
+			|state|
+			{
+				state.takeback = None;
+				state.message = String::from("Takeback refused");
+			}
+		);
+		send_err(server, "Takeback refused")?;
+	}
+
+	Ok(())
+}
+
+fn send_move(server: & Server)
+	-> Result<(), serde_json::Error>
+{
+	/* The clock decides the game if the player takes too long */
+	if server.mutate(|state| state.game.check_flag())
+	{
+		return send_flag(server);
+	}
+
+	/* Resign instead of moving if the player asked to */
+	if server.mutate(|state| state.resign)
+	{
+		server.mutate
+		(
+			|state|
+			{
+				state.game.joever = color_win
+					(& inv_color(& state.color));
+				state.message = String::from("You resigned");
+			}
+		);
+
+		/* Notify the client */
+		return send_resigned(server);
+	}
+
+	/* Ask the client to take back a move if the player asked to */
+	if server.mutate(|state| state.ask_takeback)
+	{
+		return send_takeback(server);
+	}
+
+	/* Accept the opponent's draw offer if the player asked to */
+	let accept = server.mutate
 	(
-		|state| state.message
-		= String::from("Opponent connected, waiting for handshake")
+		|state|
+		state.offer_draw
+			&& state.draw_offer == Some(inv_color(& state.color))
 	);
 
+	if accept
+	{
+		server.mutate
+		(
+			|state|
+			{
+				state.game.joever = cnp::Joever::Draw;
+				state.offer_draw = false;
+				state.draw_offer = None;
+				state.message = String::from("Draw agreed");
+			}
+		);
+
+		/* The client knows that its offer was accepted */
+		return send_draw(server);
+	}
+
+	/* Validate next move */
+	let next_move = server.mutate
+	(
+		|state|
+		{
+			match state.next_move.take()
+			{
+				Some(m) => state.game.find_move(& m),
+				None => None,
+			}
+		}
+	);
+
+	/* Keep the client aware that we are still here while the player
+	 * thinks */
+	if next_move.is_none()
+	{
+		server.peer.heartbeat()?;
+	}
+
+	if let Some(m) = next_move
+	{
+		/* Play move */
+		let offer = server.mutate
+		(
+			|state|
+			{
+				state.game.play(m);
+
+				/* Moving declines the opponent's offer */
+				if state.draw_offer
+					== Some(inv_color(& state.color))
+				{
+					state.draw_offer = None;
+				}
+
+				/* A draw can only be offered while the game is
+				 * still going */
+				let offer = state.offer_draw
+					&& state.game.joever == cnp::Joever::Ongoing;
+
+				if offer
+				{
+					state.draw_offer
+						= Some(copy_color(& state.color));
+					state.message
+						= String::from("Draw offered");
+				}
+
+				state.offer_draw = false;
+
+				if let Some(reason) = state.game.reason
+				{
+					state.message = String::from(reason);
+				}
+
+				offer
+			}
+		);
+
+		/* Send updated state to client, followed by a draw offer if
+		 * there is one */
+		send_state(server, Some(m))?;
+
+		if offer
+		{
+			send_draw(server)?;
+		}
+	}
+
+	Ok(())
+}
+
+fn recv_move(server: & Server)
+	-> Result<(), serde_json::Error>
+{
+	/* Receive message from client */
+	println!("Waiting for move");
+	let msg = match server.read::<Message<cnp::ClientToServer>>(false)
+	{
+		Ok(Message::Protocol(msg)) => msg,
+		Ok(Message::Takeback(t)) => return recv_takeback(server, t),
+		Err(e) =>
+		{
+			/* The read gives up when the client runs out of
+			 * time */
+			if server.mutate(|state| state.game.check_flag())
+			{
+				return send_flag(server);
+			}
+
+			return Err(e);
+		},
+	};
+	println!("Message received");
+
+	match msg
+	{
+		cnp::ClientToServer::Move(m) =>
+		{
+			println!("Move received");
+
+			/* Validate received move */
+			let next_move = server.mutate
+				(|state| state.game.find_move(& m));
+
+			if let Some(m) = next_move
+			{
+				/* Play move */
+				server.mutate
+				(
+					|state|
+					{
+						state.game.play(m);
+
+						/* Moving declines the offer */
+						if state.draw_offer == Some
+							(copy_color(& state.color))
+						{
+							state.draw_offer = None;
+							state.message = String::from
+								("Draw offer declined");
+						}
+
+						if let Some(reason) = state.game.reason
+						{
+							state.message
+								= String::from(reason);
+						}
+					}
+				);
+
+				/* Send updated state to the client */
+				send_state(server, Some(m))?;
+			}
+			else
+			{
+				/* Send error message */
+				send_err(server, "That move is invalid")?;
+			}
+		},
+		cnp::ClientToServer::Resign =>
+		{
+			println!("Resignation received");
+
+			/* The player wins */
+			server.mutate
+			(
+				|state|
+				{
+					state.game.joever = color_win(& state.color);
+					state.message = String
+						::from("Opponent resigned");
+				}
+			);
+
+			/* Acknowledge the resignation */
+			send_resigned(server)?;
+		},
+		cnp::ClientToServer::Draw =>
+		{
+			println!("Draw received");
+
+			/* Either accept a pending offer or register a new one,
+			 * the client sends its move after an offer and does
+			 * not wait for a response */
+			server.mutate
+			(
+				|state|
+				{
+					let color = copy_color(& state.color);
+
+					if state.draw_offer == Some(color)
+					{
+						state.game.joever = cnp::Joever::Draw;
+						state.draw_offer = None;
+						state.message = String
+							::from("Draw agreed");
+					}
+					else
+					{
+						state.draw_offer = Some
+							(inv_color(& state.color));
+						state.message = String::from
+							("Opponent offers a draw");
+					}
+				}
+			);
+		},
+	}
+
+	Ok(())
+}
+
+/* Play the game with a client that has sent its handshake */
+fn serve(server: & Server, h: ClientHandshake)
+	-> Result<(), serde_json::Error>
+{
+	server.mutate(|state| state.status = Status::Handshake);
+
 	/* Clone the stream to the state so that the owner of the driver can
 	 * shut it down if they want to quit */
 	(
-		|stream|
+		|stream: TcpStream|
 		{
 			server.mutate
 			(
-				|state| state.stream = Some(stream)
+				|state|
+				{
+					/* The player may have quit before the
+					 * stream was stored */
+					if state.quit
+					{
+						let how = std::net::Shutdown::Both;
+						let _ = stream.shutdown(how);
+					}
+
+					state.stream = Some(stream);
+				}
 			)
 		}
-	)(server.stream.try_clone().unwrap());
+	)(server.peer.stream.try_clone().unwrap());
 
-	/* Receive client handshake */
-	let h = server.read::<cnp::ClientToServerHandshake>()?;
-	println!("Client handshake received");
-
-	/* Set player color */
+	/* Set player color, a client that reconnects can not change it */
 	server.mutate
 	(
 		|state|
 		{
-			state.color = copy_color(& h.server_color);
+			state.times = h.times;
+
+			if !state.started
+			{
+				state.color = copy_color(& h.server_color);
+				state.token = h.token.clone();
+				state.started = true;
+			}
+			else if state.color != h.server_color
+			{
+				println!("Client asked for another color");
+			}
 		}
 	);
 
-	/* Send server handshake */
+	/* Send server handshake, which also brings a reconnecting client up
+	 * to date */
 	let h = server.mutate
 	(
 		|state|
 		{
 			cnp::ServerToClientHandshake
 			{
-				features	: state.features.clone(),
-				board		: state.board,
-				moves		: state.moves.clone(),
-				joever		: state.joever,
+				features	: state.features(),
+				board		: state.game.board,
+				moves		: state.game.moves.clone(),
+				joever		: state.game.joever,
 			}
 		}
 	);
 	server.write(& h)?;
 	println!("Server handshake sent");
 
-	server.mutate(|state| state.message = String::new());
+	server.mutate
+	(
+		|state|
+		{
+			state.status = Status::Connected;
+			state.game.start_clock();
+		}
+	);
+
+	/* Move loop, runs until the game is over */
+	while !server.mutate
+	(
+		|state| state.quit || state.game.joever != cnp::Joever::Ongoing
+	)
+	{
+		if server.mutate(|state| state.game.turn == state.color)
+		{
+			send_move(server)?;
+		}
+		else
+		{
+			recv_move(server)?;
+		}
+	}
+
+	/* Let the spectators know how the game ended */
+	server.mutate
+	(
+		|state|
+		{
+			let msg = state.state_msg();
+			state.broadcast(& msg);
+			state.spectators.clear();
+
+			state.game.stop_clock();
+			state.status = Status::Closed;
+		}
+	);
+
+	Ok(())
+}
+
+/* Bring a spectator up to date, add it to the list of spectators and pass
+ * on what it is sent until the game ends or it goes away */
+fn spectate(peer: net::Peer, times: bool, state_rc: Arc<Mutex<State>>)
+	-> Result<(), serde_json::Error>
+{
+	peer.stream.set_write_timeout(Some(SPECTATOR_WRITE_TIMEOUT))
+		.map_err(serde_json::Error::io)?;
+
+	let (tx, rx) = mpsc::sync_channel(SPECTATOR_BACKLOG);
+
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		/* The handshake is queued ahead of the states while the state
+		 * is locked, so that the spectator can not miss one that is
+		 * sent in the meantime */
+		let h = cnp::ServerToClientHandshake
+		{
+			features	: state.features(),
+			board		: state.game.board,
+			moves		: state.game.moves.clone(),
+			joever		: state.game.joever,
+		};
+		let _ = tx.try_send(serde_json::to_string(& h)?);
+
+		if !state.done && !state.quit
+		{
+			state.spectators.push(Spectator { tx, times });
+		}
+	}
+
+	/* The queue ends when the spectator is dropped from the list */
+	for text in rx
+	{
+		peer.write_text(& text)?;
+	}
+
+	Ok(())
+}
+
+/* Read the handshake of a new connection, which tells if the client is the
+ * player or a spectator. The player is handed to the server thread unless
+ * the game already has one, or it has started and the client does not show
+ * the token of the one that started it. */
+fn admit(peer: net::Peer,
+		state_rc: Arc<Mutex<State>>,
+		player_tx: mpsc::Sender<(net::Peer, ClientHandshake)>)
+	-> Result<(), serde_json::Error>
+{
+	let h = peer.read::<ClientHandshake, _>(true, |_| ())?;
+	println!("Client handshake received");
+
+	if h.spectate
+	{
+		println!("Spectator connected");
+
+		return spectate(peer, h.times, state_rc);
+	}
 
-	/* Move loop */
-	while !server.mutate(|state| state.quit)
+	let player =
 	{
-		if server.mutate(|state| state.turn == state.color)
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if state.player
+		{
+			Err("the game already has one")
+		}
+		else if state.started && h.token != state.token
 		{
-			send_move(& server)?;
+			Err("it is not the player that started the game")
 		}
 		else
 		{
-			recv_move(& server)?;
+			state.player = true;
+
+			Ok(())
 		}
+	};
+
+	match player
+	{
+		Ok(()) =>
+		{
+			println!("Player connected");
+
+			/* The server thread is gone if the game is over */
+			let _ = player_tx.send((peer, h));
+		},
+		Err(reason) => println!("Player refused, {}", reason),
 	}
 
 	Ok(())
 }
 
+/* Accept connections for as long as the game goes on, the handshakes are
+ * read on threads of their own so that a slow client holds up nobody */
+fn accept_main(listener: std::net::TcpListener,
+		timeout: Option<Duration>,
+		state_rc: Arc<Mutex<State>>,
+		player_tx: mpsc::Sender<(net::Peer, ClientHandshake)>)
+{
+	loop
+	{
+		let accept_result = net::accept
+		(
+			& listener,
+			||
+			{
+				let state = state_rc.lock().unwrap();
+
+				state.quit || state.done
+			},
+		);
+
+		let stream = match accept_result
+		{
+			Ok(Some((stream, _addr))) => stream,
+			Ok(None) => return,
+			Err(e) =>
+			{
+				let mut lock = state_rc.lock().unwrap();
+				let state = lock.deref_mut();
+
+				if !state.quit
+				{
+					state.status = Status
+						::ConnectFailed(e.to_string());
+				}
+
+				return;
+			},
+		};
+
+		let peer = match net::Peer::new(stream, timeout)
+		{
+			Ok(peer) => peer,
+			Err(_) => continue,
+		};
+
+		(
+			|state_rc, player_tx|
+			{
+				std::thread::spawn
+				(
+					move || admit(peer, state_rc, player_tx)
+				)
+			}
+		)(state_rc.clone(), player_tx.clone());
+	}
+}
+
+/* Wait for the acceptor to hand over a player, returns None if the player
+ * quits first */
+fn wait_player(player_rx: & mpsc::Receiver<(net::Peer, ClientHandshake)>,
+		state_rc: & Arc<Mutex<State>>)
+	-> Option<(net::Peer, ClientHandshake)>
+{
+	let poll = Duration::from_millis(50);
+
+	while !state_rc.lock().unwrap().quit
+	{
+		match player_rx.recv_timeout(poll)
+		{
+			Ok(player) => return Some(player),
+			Err(mpsc::RecvTimeoutError::Timeout) => (),
+			Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+		}
+	}
+
+	None
+}
+
+fn server_main(bind: String,
+		timeout: Option<Duration>,
+		state_rc: Arc<Mutex<State>>)
+	-> Result<(), serde_json::Error>
+{
+	println!("Entered server_main");
+
+	/* Listen for a connection */
+	let bind_result = std::net::TcpListener::bind(bind);
+	if let Err(e) = bind_result
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.status = Status::BindFailed(e.to_string());
+
+		return Ok(());
+	}
+	let listener = bind_result.unwrap();
+	println!("Server listening");
+
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.status = Status::Listening;
+	}
+
+	/* Accept connections on a separate thread so that spectators can
+	 * join at any time */
+	let (player_tx, player_rx) = mpsc::channel();
+	let acceptor =
+	(
+		|state_rc|
+		{
+			std::thread::spawn
+			(
+				move || accept_main
+				(
+					listener,
+					timeout,
+					state_rc,
+					player_tx,
+				)
+			)
+		}
+	)(state_rc.clone());
+
+	let result = play(& player_rx, & state_rc);
+
+	/* Stop accepting connections */
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		state.done = true;
+		state.spectators.clear();
+	}
+	let _ = acceptor.join();
+
+	result
+}
+
+fn play(player_rx: & mpsc::Receiver<(net::Peer, ClientHandshake)>,
+		state_rc: & Arc<Mutex<State>>)
+	-> Result<(), serde_json::Error>
+{
+	loop
+	{
+		/* Wait for a player to connect, giving up if the player quits */
+		let (peer, h) = match wait_player(player_rx, state_rc)
+		{
+			Some(player) => player,
+			None => return Ok(()),
+		};
+		println!("Server connected");
+
+		/* Create the server struct */
+		let server = Server
+		{
+			peer,
+			state_rc	: state_rc.clone(),
+		};
+
+		let result = serve(& server, h);
+
+		/* The game state is kept if the connection is lost, so that
+		 * the client can reconnect and carry on */
+		if let Err(e) = result
+		{
+			let resume = server.mutate
+			(
+				|state|
+				{
+					let resume = !state.quit
+						&& state.game.joever
+						== cnp::Joever::Ongoing;
+
+					if resume
+					{
+						let s = Status::from_error(& e);
+
+						state.status = Status::Reconnecting
+							(s.describe());
+						state.stream = None;
+						state.player = false;
+						state.responding = true;
+						state.next_move = None;
+						state.offer_draw = false;
+						state.draw_offer = None;
+						state.takebacks = false;
+						state.ask_takeback = false;
+						state.takeback = None;
+						state.answer = None;
+
+						/* Nobody's time runs while the
+						 * client is away */
+						state.game.stop_clock();
+					}
+
+					resume
+				}
+			);
+
+			if !resume
+			{
+				return Err(e);
+			}
+
+			println!("Connection lost, waiting for client");
+		}
+		else
+		{
+			return Ok(());
+		}
+	}
+}
+
+fn server_thread(bind: String,
+		timeout: Option<Duration>,
+		state_rc: Arc<Mutex<State>>)
+	-> Result<(), serde_json::Error>
+{
+	let result = server_main(bind, timeout, state_rc.clone());
+
+	/* Report the error that ended the game, unless it was caused by the
+	 * player quitting */
+	if let Err(ref e) = result
+	{
+		let mut lock = state_rc.lock().unwrap();
+		let state = lock.deref_mut();
+
+		if !state.quit
+		{
+			state.status = Status::from_error(e);
+		}
+	}
+
+	result
+}
+
 impl ServerDriver
 {
-	pub fn new() -> Self
+	/* Host a game, which has been set up with its clock but not
+	 * started */
+	pub fn new(bind: String, timeout: Option<Duration>, game: GameState)
+		-> Self
 	{
-		/* Create an empty state wrapped in an Arc Mutex */
-		let state_rc = Arc::new(Mutex::new(State::new()));
+		/* Create a state wrapped in an Arc Mutex */
+		let state_rc = Arc::new(Mutex::new(State::new(game)));
 		
 		/* Spin up a driver thread and pass the state to it */
 		let thread =
@@ -487,7 +1507,12 @@ impl ServerDriver
 			{
 				std::thread::spawn
 				(
-					|| server_main(state_rc)
+					move || server_thread
+					(
+						bind,
+						timeout,
+						state_rc,
+					)
 				)
 			}
 		)(state_rc.clone());
@@ -0,0 +1,53 @@
+extern crate serde;
+
+use self::serde::{Deserialize, Serialize};
+
+/* The feature that a server lists when it understands takeback requests */
+const FEATURE: & str = "takeback";
+
+/* The messages of the takeback extension, which are sent between those of
+ * the protocol once both ends know that the other understands them. A
+ * takeback undoes the last move of each player, so it is asked for on the
+ * player's own turn and leaves it their turn.
+ *
+ * The client says that it is Supported right after the server handshake.
+ * Either end may then send a Request on its turn. The server answers the
+ * client's request with a State after the takeback or an Error if it is
+ * refused, the client answers the server's request with Accept or Refuse
+ * and the server follows an Accept with a State. */
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Takeback
+{
+	Supported,
+	Request,
+	Accept,
+	Refuse,
+}
+
+/* A message that is either one of the protocol or one of the extension */
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Message<T>
+{
+	Protocol(T),
+	Takeback(Takeback),
+}
+
+pub fn to_feature() -> cnp::Features
+{
+	cnp::Features::Other(String::from(FEATURE))
+}
+
+/* Check the features of a server for the extension */
+pub fn in_features(features: & [cnp::Features]) -> bool
+{
+	features.contains(& to_feature())
+}
+
+/* An error for a takeback message that was not asked for, which counts as
+ * breaking the protocol */
+pub fn unexpected(t: & Takeback) -> serde_json::Error
+{
+	<serde_json::Error as serde::de::Error>::custom
+		(format!("unexpected takeback message {:?}", t))
+}
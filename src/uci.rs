@@ -0,0 +1,754 @@
+extern crate scrappy_chess;
+
+use self::scrappy_chess::chess;
+use crate::cli::default_limits;
+use crate::engine::{Engine, Thought};
+use crate::fen::square_name;
+use crate::fen;
+use crate::search::{Limits, MATE, MATE_BOUND, search};
+use crate::util::*;
+use std::io;
+use std::io::{BufRead, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/* How long an engine may take to get ready */
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+/* How long an engine may take to answer a request to stop thinking */
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+/* How long to wait for an engine to exit before killing it */
+const QUIT_TIMEOUT: Duration = Duration::from_secs(1);
+/* How often a thinking engine is checked on */
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/* How many more moves to plan for when the time left is to be shared out
+ * and the GUI does not say */
+const MOVES_TO_GO: u32 = 30;
+/* How much of the time left to keep in hand for the GUI to pass the move
+ * on */
+const MOVE_OVERHEAD: Duration = Duration::from_millis(50);
+
+fn uci_to_square(file: u8, rank: u8) -> Option<(usize, usize)>
+{
+	if (b'a'..=b'h').contains(& file) && (b'1'..=b'8').contains(& rank)
+	{
+		Some(((file - b'a') as usize, (b'8' - rank) as usize))
+	}
+	else
+	{
+		None
+	}
+}
+
+/* Write a move in UCI long algebraic notation, such as e2e4 or e7e8q */
+pub fn move_to_uci(m: & cnp::Move) -> String
+{
+	let mut s = square_name(m.start_x, m.start_y);
+	s.push_str(& square_name(m.end_x, m.end_y));
+
+	let promotion =
+	[
+		(PieceKind::Queen, 'q'),
+		(PieceKind::Rook, 'r'),
+		(PieceKind::Bishop, 'b'),
+		(PieceKind::Knight, 'n'),
+	]
+		.into_iter()
+		.find(|(k, _)| piece_is_kind(& m.promotion, *k));
+
+	if let Some((_, c)) = promotion
+	{
+		s.push(c);
+	}
+
+	s
+}
+
+/* Read a move in UCI long algebraic notation, the board tells the color of
+ * a promoted piece */
+pub fn uci_to_move(s: & str, board: & [[cnp::Piece; 8]; 8])
+	-> Option<cnp::Move>
+{
+	let b = s.as_bytes();
+
+	if b.len() != 4 && b.len() != 5
+	{
+		return None;
+	}
+
+	let (start_x, start_y) = uci_to_square(b[0], b[1])?;
+	let (end_x, end_y) = uci_to_square(b[2], b[3])?;
+	let mut promotion = cnp::Piece::None;
+
+	if let Some(c) = b.get(4)
+	{
+		let k = match c
+		{
+			b'q' => PieceKind::Queen,
+			b'r' => PieceKind::Rook,
+			b'b' => PieceKind::Bishop,
+			b'n' => PieceKind::Knight,
+			_ => return None,
+		};
+		let color = piece_color(& board[start_y][start_x])?;
+
+		promotion = piece_from_kind(& color, k);
+	}
+
+	Some(cnp::Move
+	{
+		start_x,
+		start_y,
+		end_x,
+		end_y,
+		promotion,
+	})
+}
+
+/* A go command that asks for a search within limits */
+pub fn go_command(limits: & Limits) -> String
+{
+	let mut s = String::from("go");
+
+	if let Some(depth) = limits.depth
+	{
+		s.push_str(& format!(" depth {}", depth));
+	}
+
+	if let Some(nodes) = limits.nodes
+	{
+		s.push_str(& format!(" nodes {}", nodes));
+	}
+
+	if let Some(time) = limits.time
+	{
+		s.push_str(& format!(" movetime {}", time.as_millis()));
+	}
+
+	s
+}
+
+/* The position command that sets up a game that started from the standard
+ * position */
+pub fn position_command(played: & [cnp::Move]) -> String
+{
+	let mut s = String::from("position startpos");
+
+	if !played.is_empty()
+	{
+		s.push_str(" moves");
+
+		for m in played
+		{
+			s.push(' ');
+			s.push_str(& move_to_uci(m));
+		}
+	}
+
+	s
+}
+
+/* The position command that sets up a position from its FEN */
+pub fn fen_position_command(fen: & str) -> String
+{
+	format!("position fen {}", fen)
+}
+
+/* An engine in a separate process that speaks UCI on its standard input
+ * and output */
+pub struct UciEngine
+{
+	child		: Child,
+	stdin		: ChildStdin,
+	lines		: mpsc::Receiver<String>,
+	limits		: Limits,
+}
+
+impl UciEngine
+{
+	/* Start an engine and wait for it to get ready */
+	pub fn spawn(path: & str, limits: Limits) -> io::Result<Self>
+	{
+		let mut child = Command::new(path)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+
+		let stdin = child.stdin.take().unwrap();
+		let stdout = child.stdout.take().unwrap();
+
+		/* Read the engine's output on a separate thread so that
+		 * waiting for it can time out */
+		let (tx, lines) = mpsc::channel();
+		std::thread::spawn
+		(
+			move ||
+			{
+				for line in io::BufReader::new(stdout).lines()
+				{
+					match line
+					{
+						Ok(line) =>
+						{
+							if tx.send(line).is_err()
+							{
+								break;
+							}
+						},
+						Err(_) => break,
+					}
+				}
+			}
+		);
+
+		let mut engine = Self
+		{
+			child,
+			stdin,
+			lines,
+			limits,
+		};
+
+		engine.send("uci")?;
+		engine.expect("uciok", START_TIMEOUT)?;
+		engine.send("isready")?;
+		engine.expect("readyok", START_TIMEOUT)?;
+		engine.send("ucinewgame")?;
+
+		Ok(engine)
+	}
+
+	fn send(self: & mut Self, line: & str) -> io::Result<()>
+	{
+		writeln!(self.stdin, "{}", line)?;
+		self.stdin.flush()
+	}
+
+	/* Wait for the next line of output, returns None if nothing comes
+	 * before the timeout */
+	fn recv(self: & Self, timeout: Duration) -> io::Result<Option<String>>
+	{
+		match self.lines.recv_timeout(timeout)
+		{
+			Ok(line) => Ok(Some(line)),
+			Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+			Err(mpsc::RecvTimeoutError::Disconnected) =>
+			{
+				Err(io::Error::new
+				(
+					io::ErrorKind::UnexpectedEof,
+					"the engine exited",
+				))
+			},
+		}
+	}
+
+	/* Skip output until a line that starts with a word */
+	fn expect(self: & mut Self, word: & str, timeout: Duration)
+		-> io::Result<String>
+	{
+		let start = Instant::now();
+
+		loop
+		{
+			let left = timeout.saturating_sub(start.elapsed());
+
+			match self.recv(left)?
+			{
+				Some(line) if line.split_whitespace().next()
+					== Some(word) => return Ok(line),
+				Some(_) => (),
+				None =>
+				{
+					return Err(io::Error::new
+					(
+						io::ErrorKind::TimedOut,
+						format!("the engine did not say {}", word),
+					));
+				},
+			}
+		}
+	}
+
+	/* Ask the engine for a move in the position that a position command
+	 * sets up, returns None if stop returns true first. The board tells
+	 * the color of a promoted piece. */
+	pub fn go(self: & mut Self,
+			position: & str,
+			board: & [[cnp::Piece; 8]; 8],
+			stop: & dyn Fn() -> bool)
+		-> io::Result<Option<Thought>>
+	{
+		self.send(position)?;
+		self.send(& go_command(& self.limits))?;
+
+		let mut score = None;
+		let mut stopped: Option<Instant> = None;
+
+		loop
+		{
+			if stopped.is_none() && stop()
+			{
+				self.send("stop")?;
+				stopped = Some(Instant::now());
+			}
+
+			if stopped.map_or(false, |t| t.elapsed() >= STOP_TIMEOUT)
+			{
+				return Err(io::Error::new
+				(
+					io::ErrorKind::TimedOut,
+					"the engine did not stop",
+				));
+			}
+
+			let line = match self.recv(POLL_INTERVAL)?
+			{
+				Some(line) => line,
+				None => continue,
+			};
+			let words: Vec<& str> = line.split_whitespace().collect();
+
+			match words.first()
+			{
+				Some(& "info") =>
+				{
+					if let Some(s) = parse_score(& words)
+					{
+						score = Some(s);
+					}
+				},
+				Some(& "bestmove") =>
+				{
+					/* The answer to a stop is not wanted */
+					if stopped.is_some()
+					{
+						return Ok(None);
+					}
+
+					let best = words.get(1)
+						.and_then(|s| uci_to_move(s, board))
+						.ok_or_else(|| io::Error::new
+						(
+							io::ErrorKind::InvalidData,
+							format!("bad move in '{}'", line),
+						))?;
+
+					return Ok(Some(Thought { best, score }));
+				},
+				_ => (),
+			}
+		}
+	}
+}
+
+/* Read the score from an info line, mates are turned into scores like the
+ * ones of the built in search */
+fn parse_score(words: & [& str]) -> Option<i32>
+{
+	let i = words.iter().position(|w| * w == "score")?;
+	let value: i32 = words.get(i + 2)?.parse().ok()?;
+
+	match * words.get(i + 1)?
+	{
+		"cp" => Some(value),
+		"mate" if value > 0 => Some(MATE - (value * 2 - 1)),
+		"mate" => Some(-MATE - value * 2),
+		_ => None,
+	}
+}
+
+impl Engine for UciEngine
+{
+	fn think(self: & mut Self,
+			c_state: & chess::ChessState,
+			_turn: & cnp::Color,
+			played: & [cnp::Move],
+			stop: & dyn Fn() -> bool)
+		-> Result<Option<Thought>, String>
+	{
+		let board = translate_board(c_state);
+
+		self.go(& position_command(played), & board, stop)
+			.map_err(|e| e.to_string())
+	}
+}
+
+impl Drop for UciEngine
+{
+	fn drop(self: & mut Self)
+	{
+		let _ = self.send("quit");
+
+		/* Give the engine a moment to exit by itself */
+		let start = Instant::now();
+		while start.elapsed() < QUIT_TIMEOUT
+		{
+			if let Ok(Some(_)) = self.child.try_wait()
+			{
+				return;
+			}
+
+			std::thread::sleep(POLL_INTERVAL);
+		}
+
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+/* Write a score of the built in search the way UCI info lines do */
+fn score_to_uci(score: i32) -> String
+{
+	if score >= MATE_BOUND
+	{
+		format!("mate {}", (MATE - score + 1) / 2)
+	}
+	else if score <= -MATE_BOUND
+	{
+		format!("mate -{}", (MATE + score) / 2)
+	}
+	else
+	{
+		format!("cp {}", score)
+	}
+}
+
+/* The position that position commands set up, which is not known if the
+ * last one could not be set up */
+struct Position
+{
+	c_state		: chess::ChessState,
+	turn		: cnp::Color,
+	known		: bool,
+}
+
+impl Position
+{
+	fn new() -> Self
+	{
+		Self
+		{
+			c_state		: chess::ChessState::standard(),
+			turn		: cnp::Color::White,
+			known		: true,
+		}
+	}
+
+	/* Set up the position of a position command. The move generation
+	 * can only start from the standard position, so a FEN is only taken
+	 * if it is that one. */
+	fn set(self: & mut Self, words: & [& str])
+	{
+		* self = Self::new();
+		self.known = false;
+
+		let moves = match words.get(1)
+		{
+			Some(& "startpos") => 2,
+			Some(& "fen") =>
+			{
+				let end = words.iter()
+					.position(|w| * w == "moves")
+					.unwrap_or(words.len());
+
+				match fen::parse(& words[2..end].join(" "))
+				{
+					Ok(fen) if fen.is_standard() => end,
+					Ok(_) =>
+					{
+						println!("info string only the \
+							standard position is supported");
+						return;
+					},
+					Err(e) =>
+					{
+						println!("info string bad fen, {}", e);
+						return;
+					},
+				}
+			},
+			_ => return,
+		};
+
+		if words.get(moves) != Some(& "moves")
+		{
+			self.known = true;
+
+			return;
+		}
+
+		let moveset = chess::MoveSet::new();
+
+		for s in & words[moves + 1..]
+		{
+			let board = translate_board(& self.c_state);
+			let c_moves = self.c_state.get_moves(& moveset);
+			let cm = uci_to_move(s, & board)
+				.and_then(|m| match_move(& board, & m, & c_moves));
+
+			match cm
+			{
+				Some(cm) =>
+				{
+					self.c_state = cm.result;
+					self.turn = inv_color(& self.turn);
+				},
+				None =>
+				{
+					println!("info string illegal move {}", s);
+					return;
+				},
+			}
+		}
+
+		self.known = true;
+	}
+}
+
+/* Read the limits of a go command, returns them along with whether to keep
+ * searching until told to stop */
+fn parse_go(words: & [& str], turn: & cnp::Color) -> (Limits, bool)
+{
+	let mut limits = Limits
+	{
+		depth	: None,
+		nodes	: None,
+		time	: None,
+	};
+	let mut infinite = false;
+	let mut time_left = None;
+	let mut increment = Duration::ZERO;
+	let mut moves_to_go = MOVES_TO_GO;
+
+	let (time_word, inc_word) = match turn
+	{
+		cnp::Color::White => ("wtime", "winc"),
+		cnp::Color::Black => ("btime", "binc"),
+	};
+
+	let mut i = 1;
+	while i < words.len()
+	{
+		let value = words.get(i + 1).and_then(|w| w.parse::<u64>().ok());
+
+		match words[i]
+		{
+			"infinite" => infinite = true,
+			"depth" => limits.depth = value.map(|v| v as u32),
+			"nodes" => limits.nodes = value,
+			"movetime" => limits.time = value.map(Duration::from_millis),
+			"movestogo" =>
+			{
+				moves_to_go = value.map_or(MOVES_TO_GO, |v| v as u32);
+			},
+			w if w == time_word =>
+			{
+				time_left = value.map(Duration::from_millis);
+			},
+			w if w == inc_word =>
+			{
+				increment = value.map_or
+					(Duration::ZERO, Duration::from_millis);
+			},
+			_ => (),
+		}
+
+		i += 1;
+	}
+
+	/* Share the time left out over the moves to come, but never use
+	 * more than is left */
+	if let (Some(left), None) = (time_left, limits.time)
+	{
+		let left = left.saturating_sub(MOVE_OVERHEAD);
+		let share = left / moves_to_go.max(1) + increment;
+
+		limits.time = Some(share
+			.min(left / 2)
+			.max(Duration::from_millis(1)));
+	}
+
+	/* A go without any limits gets the ones the game uses */
+	if !infinite
+		&& limits.depth.is_none()
+		&& limits.nodes.is_none()
+		&& limits.time.is_none()
+	{
+		limits = default_limits();
+	}
+
+	(limits, infinite)
+}
+
+/* Search on a separate thread, so that a stop can be read in the
+ * meantime, and print the best move when done */
+fn start_search(position: & Position,
+		limits: Limits,
+		infinite: bool,
+		stop_rc: Arc<Mutex<bool>>)
+	-> JoinHandle<()>
+{
+	let c_state = position.c_state;
+	let turn = copy_color(& position.turn);
+
+	std::thread::spawn
+	(
+		move ||
+		{
+			let stopped = || * stop_rc.lock().unwrap();
+			let result = search(& c_state, & turn, & limits, stopped);
+
+			/* An infinite search only answers when it is stopped */
+			while infinite && !stopped()
+			{
+				std::thread::sleep(POLL_INTERVAL);
+			}
+
+			match result
+			{
+				Some(result) =>
+				{
+					let board = translate_board(& c_state);
+					let best = translate_move(& board, & result.best);
+
+					println!
+					(
+						"info depth {} score {} nodes {} pv {}",
+						result.depth,
+						score_to_uci(result.score),
+						result.nodes,
+						move_to_uci(& best),
+					);
+					println!("bestmove {}", move_to_uci(& best));
+				},
+				None => println!("bestmove 0000"),
+			}
+		}
+	)
+}
+
+/* Stop a search if one is going on and wait for it to print its move */
+fn stop_search(thread: & mut Option<JoinHandle<()>>,
+		stop_rc: & Arc<Mutex<bool>>)
+{
+	if let Some(thread) = thread.take()
+	{
+		* stop_rc.lock().unwrap() = true;
+		let _ = thread.join();
+	}
+}
+
+/* Act as a UCI engine on the standard input and output, with the built in
+ * search, until told to quit or the input ends */
+pub fn uci_main() -> io::Result<()>
+{
+	let mut position = Position::new();
+	let mut thread: Option<JoinHandle<()>> = None;
+	let stop_rc = Arc::new(Mutex::new(false));
+
+	for line in io::stdin().lock().lines()
+	{
+		let line = line?;
+		let words: Vec<& str> = line.split_whitespace().collect();
+
+		match words.first()
+		{
+			Some(& "uci") =>
+			{
+				println!("id name tombergm-chess-gui");
+				println!("id author tombergm");
+				println!("uciok");
+			},
+			Some(& "isready") => println!("readyok"),
+			Some(& "ucinewgame") =>
+			{
+				stop_search(& mut thread, & stop_rc);
+				position = Position::new();
+			},
+			Some(& "position") =>
+			{
+				stop_search(& mut thread, & stop_rc);
+				position.set(& words);
+			},
+			Some(& "go") =>
+			{
+				stop_search(& mut thread, & stop_rc);
+
+				/* There is no move to give in a position that
+				 * could not be set up */
+				if !position.known
+				{
+					println!("bestmove 0000");
+					continue;
+				}
+
+				let (limits, infinite) = parse_go
+					(& words, & position.turn);
+
+				* stop_rc.lock().unwrap() = false;
+				thread = Some(start_search
+				(
+					& position,
+					limits,
+					infinite,
+					stop_rc.clone(),
+				));
+			},
+			Some(& "stop") => stop_search(& mut thread, & stop_rc),
+			Some(& "quit") => break,
+			/* Options, debugging and the like are not supported,
+			 * and unknown commands are to be ignored */
+			_ => (),
+		}
+	}
+
+	stop_search(& mut thread, & stop_rc);
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn set(command: & str) -> Position
+	{
+		let words: Vec<& str> = command.split_whitespace().collect();
+		let mut position = Position::new();
+
+		position.set(& words);
+
+		position
+	}
+
+	#[test]
+	fn standard_fen_is_known()
+	{
+		let position = set("position fen \
+			rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+
+		assert!(position.known);
+		assert_eq!(position.turn, cnp::Color::White);
+	}
+
+	/* The move generation can only start from the standard position, so
+	 * any other one is refused and a go gets no move */
+	#[test]
+	fn other_fen_is_not_known()
+	{
+		let position = set("position fen \
+			4k3/8/8/8/8/8/4P3/4K3 w - - 0 1 moves e2e4");
+
+		assert!(!position.known);
+	}
+
+	#[test]
+	fn bad_fen_is_not_known()
+	{
+		assert!(!set("position fen 8/8/8 w - - 0 1").known);
+		assert!(!set("position").known);
+	}
+}
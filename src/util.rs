@@ -21,6 +21,64 @@ pub fn inv_color(c: & cnp::Color) -> cnp::Color
 	}
 }
 
+pub fn random_color() -> cnp::Color
+{
+	use std::hash::{BuildHasher, Hasher};
+
+	/* The standard library seeds its hash keys randomly, which is good
+	 * enough for a coin flip */
+	let r = std::collections::hash_map::RandomState::new()
+		.build_hasher()
+		.finish();
+
+	if r & 1 == 0
+	{
+		cnp::Color::White
+	}
+	else
+	{
+		cnp::Color::Black
+	}
+}
+
+pub fn color_win(c: & cnp::Color) -> cnp::Joever
+{
+	match c
+	{
+		cnp::Color::White => cnp::Joever::White,
+		cnp::Color::Black => cnp::Joever::Black,
+	}
+}
+
+pub fn piece_color(p: & cnp::Piece) -> Option<cnp::Color>
+{
+	match p
+	{
+		cnp::Piece::WhiteKing
+			| cnp::Piece::WhiteQueen
+			| cnp::Piece::WhiteRook
+			| cnp::Piece::WhiteBishop
+			| cnp::Piece::WhiteKnight
+			| cnp::Piece::WhitePawn => Some(cnp::Color::White),
+		cnp::Piece::BlackKing
+			| cnp::Piece::BlackQueen
+			| cnp::Piece::BlackRook
+			| cnp::Piece::BlackBishop
+			| cnp::Piece::BlackKnight
+			| cnp::Piece::BlackPawn => Some(cnp::Color::Black),
+		cnp::Piece::None => None,
+	}
+}
+
+/* Work out whose turn it is from the moves that are available */
+pub fn moves_turn(board: & [[cnp::Piece; 8]; 8], moves: & [cnp::Move])
+	-> Option<cnp::Color>
+{
+	let m = moves.first()?;
+
+	piece_color(& board[m.start_y][m.start_x])
+}
+
 pub fn char_to_piece(c: u8) -> cnp::Piece
 {
 	match c as char
@@ -41,37 +99,172 @@ pub fn char_to_piece(c: u8) -> cnp::Piece
 	}
 }
 
-pub fn translate_move(m: & chess::Move) -> cnp::Move
+pub fn piece_to_char(p: & cnp::Piece) -> char
 {
-	let start_x = 7 - ((m.from >> 0) & 0x7);
-	let start_y = 7 - ((m.from >> 3) & 0x7);
-	let end_x = 7 - ((m.to >> 0) & 0x7);
-	let end_y = 7 - ((m.to >> 3) & 0x7);
+	match p
+	{
+		cnp::Piece::WhiteKing	=> 'K',
+		cnp::Piece::WhiteQueen	=> 'Q',
+		cnp::Piece::WhiteRook	=> 'R',
+		cnp::Piece::WhiteBishop	=> 'B',
+		cnp::Piece::WhiteKnight	=> 'N',
+		cnp::Piece::WhitePawn	=> 'P',
+		cnp::Piece::BlackKing	=> 'k',
+		cnp::Piece::BlackQueen	=> 'q',
+		cnp::Piece::BlackRook	=> 'r',
+		cnp::Piece::BlackBishop	=> 'b',
+		cnp::Piece::BlackKnight	=> 'n',
+		cnp::Piece::BlackPawn	=> 'p',
+		cnp::Piece::None	=> '.',
+	}
+}
+
+/* Translate a move, the board from before the move is needed to tell which
+ * piece a pawn is promoted to */
+pub fn translate_move(board: & [[cnp::Piece; 8]; 8], m: & chess::Move)
+	-> cnp::Move
+{
+	let start_x = (7 - ((m.from >> 0) & 0x7)) as usize;
+	let start_y = (7 - ((m.from >> 3) & 0x7)) as usize;
+	let end_x = (7 - ((m.to >> 0) & 0x7)) as usize;
+	let end_y = (7 - ((m.to >> 3) & 0x7)) as usize;
+	let mut promotion = cnp::Piece::None;
+
+	/* The move only holds the resulting position, so the promotion has to
+	 * be read off the square the pawn lands on */
+	if piece_is_kind(& board[start_y][start_x], PieceKind::Pawn)
+		&& (end_y == 0 || end_y == 7)
+	{
+		promotion = translate_board(& m.result)[end_y][end_x];
+	}
 
 	cnp::Move
 	{
-		start_x		: start_x as usize,
-		start_y		: start_y as usize,
-		end_x		: end_x as usize,
-		end_y		: end_y as usize,
-		promotion	: cnp::Piece::None,
+		start_x,
+		start_y,
+		end_x,
+		end_y,
+		promotion,
+	}
+}
+
+/* A copy of one of the rules engine's moves */
+pub fn copy_move(cm: & chess::Move) -> chess::Move
+{
+	chess::Move
+	{
+		result	: cm.result,
+		from	: cm.from,
+		to	: cm.to,
 	}
 }
 
-pub fn match_move(m: & cnp::Move, c_moves: & Vec<chess::Move>)
+/* Find the move that matches a protocol move, including the piece that a
+ * pawn is promoted to */
+pub fn match_move(board: & [[cnp::Piece; 8]; 8],
+			m: & cnp::Move,
+			c_moves: & Vec<chess::Move>)
 	-> Option<chess::Move>
 {
 	for cm in c_moves
 	{
-		if *m == translate_move(cm)
+		if *m == translate_move(board, cm)
+		{
+			return Some(copy_move(cm));
+		}
+	}
+
+	None
+}
+
+/* The board after a move, castling moves the rook along with the king and
+ * en passant takes the pawn that was passed */
+pub fn apply_move(board: & [[cnp::Piece; 8]; 8], m: & cnp::Move)
+	-> [[cnp::Piece; 8]; 8]
+{
+	let mut board = * board;
+	let piece = board[m.start_y][m.start_x];
+
+	if piece_is_kind(& piece, PieceKind::King)
+		&& m.start_x.abs_diff(m.end_x) == 2
+	{
+		let (from, to) = if m.end_x > m.start_x
+		{
+			(7, 5)
+		}
+		else
+		{
+			(0, 3)
+		};
+
+		board[m.start_y][to] = board[m.start_y][from];
+		board[m.start_y][from] = cnp::Piece::None;
+	}
+
+	if piece_is_kind(& piece, PieceKind::Pawn)
+		&& m.start_x != m.end_x
+		&& board[m.end_y][m.end_x] == cnp::Piece::None
+	{
+		board[m.start_y][m.end_x] = cnp::Piece::None;
+	}
+
+	if m.promotion != cnp::Piece::None
+	{
+		board[m.end_y][m.end_x] = m.promotion;
+	}
+	else
+	{
+		board[m.end_y][m.end_x] = piece;
+	}
+
+	board[m.start_y][m.start_x] = cnp::Piece::None;
+
+	board
+}
+
+/* Work out the move that leads from one board to another, for when the
+ * legal moves are not known. The piece that moves leaves its square empty
+ * and lands on another one that changed. */
+pub fn move_between(before: & [[cnp::Piece; 8]; 8],
+			after: & [[cnp::Piece; 8]; 8])
+	-> Option<cnp::Move>
+{
+	let changed: Vec<(usize, usize)> = (0..64)
+		.map(|i| (i % 8, i / 8))
+		.filter(|& (x, y)| before[y][x] != after[y][x])
+		.collect();
+
+	for & (start_x, start_y) in & changed
+	{
+		let piece = before[start_y][start_x];
+
+		if piece == cnp::Piece::None
+			|| after[start_y][start_x] != cnp::Piece::None
 		{
-			let cm = chess::Move
+			continue;
+		}
+
+		for & (end_x, end_y) in & changed
+		{
+			let mut m = cnp::Move
 			{
-				result	: cm.result,
-				from	: cm.from,
-				to	: cm.to,
+				start_x,
+				start_y,
+				end_x,
+				end_y,
+				promotion	: cnp::Piece::None,
 			};
-			return Some(cm);
+
+			if piece_is_kind(& piece, PieceKind::Pawn)
+				&& (end_y == 0 || end_y == 7)
+			{
+				m.promotion = after[end_y][end_x];
+			}
+
+			if apply_move(before, & m) == * after
+			{
+				return Some(m);
+			}
 		}
 	}
 
@@ -91,6 +284,171 @@ pub fn translate_board(c_state: & chess::ChessState) -> [[cnp::Piece; 8]; 8]
 	board
 }
 
+fn piece_at(board: & [[cnp::Piece; 8]; 8], x: i32, y: i32)
+	-> Option<cnp::Piece>
+{
+	if x < 0 || y < 0 || x > 7 || y > 7
+	{
+		None
+	}
+	else
+	{
+		Some(board[y as usize][x as usize])
+	}
+}
+
+/* Check if a square is attacked by any piece of the given color */
+pub fn is_attacked(board: & [[cnp::Piece; 8]; 8], x: i32, y: i32,
+			by: & cnp::Color)
+	-> bool
+{
+	let attacker = |x, y, kinds: & [PieceKind]|
+	{
+		match piece_at(board, x, y)
+		{
+			Some(p) => piece_color(& p).as_ref() == Some(by)
+				&& kinds.iter().any(|k| piece_is_kind(& p, *k)),
+			None => false,
+		}
+	};
+
+	/* White pawns move towards the top of the board, so they attack
+	 * from below */
+	let pawn_dy = match by
+	{
+		cnp::Color::White => 1,
+		cnp::Color::Black => -1,
+	};
+	for dx in [-1, 1]
+	{
+		if attacker(x + dx, y + pawn_dy, & [PieceKind::Pawn])
+		{
+			return true;
+		}
+	}
+
+	for (dx, dy) in
+	[
+		(1, 2), (2, 1), (2, -1), (1, -2),
+		(-1, -2), (-2, -1), (-2, 1), (-1, 2),
+	]
+	{
+		if attacker(x + dx, y + dy, & [PieceKind::Knight])
+		{
+			return true;
+		}
+	}
+
+	for (dx, dy) in
+	[
+		(1, 0), (0, 1), (-1, 0), (0, -1),
+		(1, 1), (1, -1), (-1, -1), (-1, 1),
+	]
+	{
+		if attacker(x + dx, y + dy, & [PieceKind::King])
+		{
+			return true;
+		}
+
+		let sliders = if dx == 0 || dy == 0
+		{
+			[PieceKind::Rook, PieceKind::Queen]
+		}
+		else
+		{
+			[PieceKind::Bishop, PieceKind::Queen]
+		};
+
+		/* Walk along the line until a piece blocks it */
+		let (mut sx, mut sy) = (x + dx, y + dy);
+		while let Some(p) = piece_at(board, sx, sy)
+		{
+			if p != cnp::Piece::None
+			{
+				if attacker(sx, sy, & sliders)
+				{
+					return true;
+				}
+
+				break;
+			}
+
+			sx += dx;
+			sy += dy;
+		}
+	}
+
+	false
+}
+
+/* Check if the king of the given color is in check */
+pub fn in_check(board: & [[cnp::Piece; 8]; 8], c: & cnp::Color) -> bool
+{
+	let king = piece_from_kind(c, PieceKind::King);
+
+	for y in 0..8
+	{
+		for x in 0..8
+		{
+			if board[y][x] == king
+			{
+				return is_attacked
+				(
+					board,
+					x as i32,
+					y as i32,
+					& inv_color(c),
+				);
+			}
+		}
+	}
+
+	false
+}
+
+/* Check if neither player has the material left to give checkmate */
+pub fn insufficient_material(board: & [[cnp::Piece; 8]; 8]) -> bool
+{
+	let mut minors = 0;
+	let mut knights = 0;
+	let mut bishop_squares = [false; 2];
+
+	for y in 0..8
+	{
+		for x in 0..8
+		{
+			let p = & board[y][x];
+
+			if * p == cnp::Piece::None
+				|| piece_is_kind(p, PieceKind::King)
+			{
+				continue;
+			}
+
+			if piece_is_kind(p, PieceKind::Knight)
+			{
+				knights += 1;
+			}
+			else if piece_is_kind(p, PieceKind::Bishop)
+			{
+				bishop_squares[(x + y) % 2] = true;
+			}
+			else
+			{
+				return false;
+			}
+
+			minors += 1;
+		}
+	}
+
+	/* A lone minor piece can not mate, and neither can any number of
+	 * bishops that all stand on squares of the same color */
+	minors <= 1
+		|| (knights == 0
+			&& !(bishop_squares[0] && bishop_squares[1]))
+}
+
 pub fn default_board() -> [[cnp::Piece; 8]; 8]
 {
 	translate_board(& chess::ChessState::default())
@@ -0,0 +1,91 @@
+extern crate chess_network_protocol as cnp;
+extern crate scrappy_chess;
+extern crate tombergm_chess_gui;
+
+use scrappy_chess::chess;
+use tombergm_chess_gui::san::san_to_move;
+use tombergm_chess_gui::util::*;
+
+/* Leaves a white pawn on b7 that can take the rook on a8, and a black pawn
+ * on g2 that can take on f1 and h1 or go to g1 */
+const OPENING: & [& str] =
+&[
+	"a4", "b5", "axb5", "a6", "bxa6", "Bb7", "axb7",
+	"h5", "g4", "hxg4", "Nf3", "g3", "e3", "g2",
+];
+
+/* Play moves in standard algebraic notation from the standard position */
+fn play(sans: & [& str]) -> chess::ChessState
+{
+	let mut c_state = chess::ChessState::standard();
+	let moveset = chess::MoveSet::new();
+
+	for san in sans
+	{
+		let board = translate_board(& c_state);
+		let c_moves = c_state.get_moves(& moveset);
+		let moves: Vec<cnp::Move> = c_moves
+			.iter()
+			.map(|cm| translate_move(& board, cm))
+			.collect();
+
+		let m = san_to_move(san, & board, & moves).unwrap();
+		c_state = match_move(& board, & m, & c_moves).unwrap().result;
+	}
+
+	c_state
+}
+
+/* Check that every kind of promotion is there for the player to move, and
+ * that each one comes back as the same move and position after a trip from
+ * the rules engine to the protocol and back */
+fn round_trip(c_state: & chess::ChessState, c: cnp::Color)
+{
+	let board = translate_board(c_state);
+	let c_moves = c_state.get_moves(& chess::MoveSet::new());
+
+	for kind in
+	[
+		PieceKind::Queen,
+		PieceKind::Rook,
+		PieceKind::Bishop,
+		PieceKind::Knight,
+	]
+	{
+		let promotion = piece_from_kind(& c, kind);
+		let moves: Vec<cnp::Move> = c_moves
+			.iter()
+			.map(|cm| translate_move(& board, cm))
+			.filter(|m| m.promotion == promotion)
+			.collect();
+
+		assert!(!moves.is_empty(), "no promotion to {:?}", promotion);
+
+		for m in moves
+		{
+			let cm = match_move(& board, & m, & c_moves).unwrap();
+
+			assert_eq!(translate_move(& board, & cm), m);
+			assert_eq!
+			(
+				translate_board(& cm.result),
+				apply_move(& board, & m),
+			);
+		}
+	}
+}
+
+#[test]
+fn white_promotions()
+{
+	round_trip(& play(OPENING), cnp::Color::White);
+}
+
+#[test]
+fn black_promotions()
+{
+	let mut sans = OPENING.to_vec();
+	sans.push("Nc3");
+
+	round_trip(& play(& sans), cnp::Color::Black);
+}
@@ -0,0 +1,156 @@
+#![cfg(unix)]
+
+extern crate chess_network_protocol as cnp;
+extern crate tombergm_chess_gui;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tombergm_chess_gui::search::Limits;
+use tombergm_chess_gui::uci::UciEngine;
+use tombergm_chess_gui::util::default_board;
+
+const LIMITS: Limits = Limits
+{
+	depth	: Some(3),
+	nodes	: None,
+	time	: None,
+};
+
+/* Write a shell script that stands in for an engine */
+fn script(name: & str, body: & str) -> PathBuf
+{
+	let dir = std::env::temp_dir()
+		.join(format!("uci-test-{}", std::process::id()));
+	std::fs::create_dir_all(& dir).unwrap();
+
+	let path = dir.join(name);
+	std::fs::write(& path, body).unwrap();
+	std::fs::set_permissions(& path, std::fs::Permissions::from_mode(0o755))
+		.unwrap();
+
+	path
+}
+
+/* An engine that gets through the handshake and then does what on_go says
+ * when it is told to go. Everything it is sent is written to a log next to
+ * it. */
+fn fake_engine(name: & str, on_go: & str) -> PathBuf
+{
+	let body = format!
+	(
+		"#!/bin/sh\n\
+		while read -r line; do\n\
+			echo \"$line\" >> \"$0.log\"\n\
+			case \"$line\" in\n\
+			uci) echo 'id name Fake'; echo uciok ;;\n\
+			isready) echo readyok ;;\n\
+			go*) {} ;;\n\
+			stop) echo 'bestmove e2e4' ;;\n\
+			quit) exit 0 ;;\n\
+			esac\n\
+		done\n",
+		on_go,
+	);
+
+	script(name, & body)
+}
+
+/* The lines that the engine was sent */
+fn sent(path: & Path) -> Vec<String>
+{
+	let log = path.with_extension("log");
+
+	std::fs::read_to_string(log)
+		.unwrap()
+		.lines()
+		.map(String::from)
+		.collect()
+}
+
+#[test]
+fn handshake_and_bestmove()
+{
+	let path = fake_engine
+	(
+		"bestmove",
+		"echo 'info depth 1 score cp 25'; echo 'bestmove e2e4'",
+	);
+	let mut engine = UciEngine::spawn(path.to_str().unwrap(), LIMITS)
+		.unwrap();
+
+	let thought = engine
+		.go("position startpos", & default_board(), & || false)
+		.unwrap()
+		.unwrap();
+
+	assert_eq!
+	(
+		thought.best,
+		cnp::Move
+		{
+			start_x		: 4,
+			start_y		: 6,
+			end_x		: 4,
+			end_y		: 4,
+			promotion	: cnp::Piece::None,
+		},
+	);
+	assert_eq!(thought.score, Some(25));
+
+	drop(engine);
+	assert_eq!
+	(
+		sent(& path),
+		[
+			"uci",
+			"isready",
+			"ucinewgame",
+			"position startpos",
+			"go depth 3",
+			"quit",
+		],
+	);
+}
+
+#[test]
+fn stop()
+{
+	/* The engine thinks until it is told to stop */
+	let path = fake_engine("stop", ":");
+	let mut engine = UciEngine::spawn(path.to_str().unwrap(), LIMITS)
+		.unwrap();
+
+	let thought = engine
+		.go("position startpos", & default_board(), & || true)
+		.unwrap();
+
+	assert!(thought.is_none());
+
+	drop(engine);
+	assert!(sent(& path).contains(& String::from("stop")));
+}
+
+#[test]
+fn engine_dies()
+{
+	let path = fake_engine("dies", "exit 1");
+	let mut engine = UciEngine::spawn(path.to_str().unwrap(), LIMITS)
+		.unwrap();
+
+	let result = engine
+		.go("position startpos", & default_board(), & || false);
+
+	assert_eq!
+	(
+		result.err().map(|e| e.kind()),
+		Some(std::io::ErrorKind::UnexpectedEof),
+	);
+}
+
+#[test]
+fn engine_dies_in_handshake()
+{
+	let path = script("dies-early", "#!/bin/sh\nread -r line\nexit 1\n");
+
+	assert!(UciEngine::spawn(path.to_str().unwrap(), LIMITS).is_err());
+}